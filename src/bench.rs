@@ -0,0 +1,135 @@
+//! The bench subcommand: runs xtemp on generated input with different batch sizes, places to
+//! keep tempfiles (directories and, on Linux, memfds) and ways of writing them, and prints how
+//! long each took. Each run is a separate xtemp process, so that it's timed end to end just as
+//! it would be used.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::{log, sys, Result, XtempError};
+
+/// Ways of writing tempfiles that are compared, and the options that select them
+const STRATEGIES: &[(&str, &[&str])] = &[
+    ("plain", &[]),
+    ("4 write threads", &["--write-threads", "4"]),
+    ("double buffer", &["--double-buffer"]),
+    ("fsync", &["--fsync"]),
+];
+
+/// Where the tempfiles are kept in a run: in a directory, or in memory with --memfd
+struct Backend {
+    name: String,
+    dir: Option<PathBuf>,
+    args: &'static [&'static str],
+}
+
+pub struct Options {
+    pub records: usize,
+    /// Size of each record, not counting its newline
+    pub record_size: u64,
+    pub batch_sizes: Vec<usize>,
+    /// Directories to put the tempfiles in, or none for the defaults
+    pub dirs: Vec<PathBuf>,
+    pub command: Vec<OsString>,
+}
+
+pub fn run(options: &Options) -> Result<()> {
+    let exe = std::env::current_exe()
+        .map_err(|e| XtempError::SubprocessFailed(format!("can't find xtemp itself: {}", e)))?;
+    let scratch = tempfile::tempdir().map_err(XtempError::FailedToWrite)?;
+    let input = scratch.path().join("input");
+    write_input(&input, options.records, options.record_size).map_err(XtempError::FailedToWrite)?;
+    let bytes = options.records as f64 * (options.record_size + 1) as f64;
+
+    let dirs = if options.dirs.is_empty() { default_dirs() } else { options.dirs.clone() };
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut backends: Vec<Backend> = dirs
+        .into_iter()
+        .map(|dir| Backend { name: dir.display().to_string(), dir: Some(dir), args: &[] })
+        .collect();
+    #[cfg(target_os = "linux")]
+    backends.push(Backend { name: "memfd".into(), dir: None, args: &["--memfd"] });
+    let mut stdout = io::stdout().lock();
+    let mut row = |row: String| {
+        writeln!(stdout, "{}", row).and_then(|_| stdout.flush()).map_err(XtempError::FailedToWrite)
+    };
+    row(format!(
+        "{:<24} {:>10} {:<16} {:>9} {:>12} {:>9}",
+        "tempfiles in", "batch size", "writing", "seconds", "lines/s", "MiB/s"
+    ))?;
+    for backend in &backends {
+        for &batch_size in &options.batch_sizes {
+            for &(strategy, strategy_args) in STRATEGIES {
+                // There's nothing to sync in memory
+                if backend.dir.is_none() && strategy_args.contains(&"--fsync") {
+                    continue;
+                }
+                log::info(&format!(
+                    "running {} with -n {} in {}",
+                    strategy, batch_size, backend.name
+                ));
+                let mut command = Command::new(&exe);
+                // Neither the user's defaults nor their diagnostics should skew the results
+                command
+                    .env("XTEMP_CONFIG", scratch.path().join("no-config"))
+                    .env_remove("XTEMP_OPTS");
+                if let Some(ref dir) = backend.dir {
+                    command.env("TMPDIR", dir).env("TMP", dir).env("TEMP", dir);
+                }
+                let started = Instant::now();
+                let status = command
+                    .args(["--quiet", "-n", &batch_size.to_string()])
+                    .args(backend.args)
+                    .args(strategy_args)
+                    .arg("--")
+                    .args(&options.command)
+                    .stdin(File::open(&input).map_err(XtempError::FailedToWrite)?)
+                    .stdout(Stdio::null())
+                    .status()
+                    .map_err(|e| XtempError::SubprocessFailed(e.to_string()))?;
+                let secs = started.elapsed().as_secs_f64();
+                let result = if status.success() {
+                    format!(
+                        "{:>9.3} {:>12.0} {:>9.1}",
+                        secs,
+                        options.records as f64 / secs,
+                        bytes / secs / (1024.0 * 1024.0)
+                    )
+                } else {
+                    format!("failed with code {}", sys::exit_code(status))
+                };
+                let name = &backend.name;
+                row(format!("{:<24} {:>10} {:<16} {}", name, batch_size, strategy, result))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `records` lines of `size` bytes each to `path`, each different from the last.
+fn write_input(path: &Path, records: usize, size: u64) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut line = Vec::with_capacity(size as usize + 1);
+    for i in 0..records {
+        line.clear();
+        line.extend_from_slice(i.to_string().as_bytes());
+        line.resize(size as usize, b'x');
+        line.push(b'\n');
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+/// The system temporary directory and, where there is one, the tmpfs at /dev/shm.
+fn default_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![std::env::temp_dir()];
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() && dirs[0] != shm {
+        dirs.push(shm.to_path_buf());
+    }
+    dirs
+}
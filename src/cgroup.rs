@@ -0,0 +1,150 @@
+//! A cgroup of its own for each batch's command with --cgroup, so that limits apply to
+//! everything the command starts and anything it leaves behind is killed along with it.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::log;
+
+/// The cgroup(7) period that CPU limits are expressed over, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// How many times to try removing a cgroup while its killed processes exit, 10ms apart
+const REMOVE_ATTEMPTS: usize = 100;
+
+/// Cgroup settings for --cgroup-memory-max and --cgroup-cpu-max
+pub struct Limits {
+    /// memory.max, in bytes
+    pub memory: Option<u64>,
+    /// cpu.max, as a number of CPUs
+    pub cpus: Option<f64>,
+}
+
+/// A cgroup that's removed when dropped, killing anything still in it first
+pub struct Cgroup {
+    path: PathBuf,
+    /// The cgroup's cgroup.procs, which the command adds itself to before it execs
+    procs: File,
+}
+
+impl Cgroup {
+    /// Creates a cgroup named `name` under `parent` with `limits` applied, enabling the
+    /// controllers they need in `parent` if they aren't already.
+    pub fn create(parent: &Path, name: &str, limits: &Limits) -> io::Result<Self> {
+        let path = parent.join(name);
+        std::fs::create_dir(&path)?;
+        let setup = || {
+            if let Some(memory) = limits.memory {
+                set(parent, &path, "memory", "memory.max", &memory.to_string())?;
+            }
+            if let Some(cpus) = limits.cpus {
+                let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+                set(parent, &path, "cpu", "cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+            }
+            File::options().write(true).open(path.join("cgroup.procs"))
+        };
+        match setup() {
+            Ok(procs) => Ok(Cgroup { path, procs }),
+            Err(e) => {
+                let _ = std::fs::remove_dir(&path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Makes `cmd` join the cgroup before it execs, so that it's in the cgroup before it can
+    /// start anything else.
+    pub fn apply(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        let fd = self.procs.as_raw_fd();
+        // Safety: write(2) is async-signal-safe, and the descriptor stays open until the cgroup
+        // is dropped, after the command has exited. Writing 0 moves the writer itself
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::write(fd, b"0".as_ptr().cast(), 1) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // cgroup.kill is new in Linux 5.14; before that each process has to be killed in turn
+        if std::fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+            if let Ok(procs) = std::fs::read_to_string(self.path.join("cgroup.procs")) {
+                for pid in procs.lines().filter_map(|line| line.parse().ok()) {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+            }
+        }
+        for _ in 0..REMOVE_ATTEMPTS {
+            match std::fs::remove_dir(&self.path) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => return,
+            }
+        }
+        log::error(&format!("failed to remove cgroup {}", self.path.display()));
+    }
+}
+
+/// Writes one of a cgroup's settings, first enabling its controller in the parent if the
+/// setting isn't there.
+fn set(parent: &Path, path: &Path, controller: &str, setting: &str, value: &str) -> io::Result<()> {
+    let write = || std::fs::write(path.join(setting), value);
+    match write() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            std::fs::write(parent.join("cgroup.subtree_control"), format!("+{}", controller))?;
+            write()
+        }
+        result => result,
+    }
+}
+
+/// Moves xtemp into a leaf cgroup under `own`, its own cgroup, so that controllers can be
+/// enabled there for the batches' cgroups: cgroup(7) doesn't allow that for a cgroup that has
+/// processes of its own.
+pub fn leave(own: &Path) -> io::Result<()> {
+    let leaf = own.join("xtemp-main");
+    match std::fs::create_dir(&leaf) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => (),
+    }
+    std::fs::write(leaf.join("cgroup.procs"), "0")
+}
+
+/// Returns the directory of the cgroup (v2) that xtemp itself is in.
+pub fn own() -> io::Result<PathBuf> {
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, "cgroup v2 is not mounted");
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+    let own = cgroups.lines().find_map(|line| line.strip_prefix("0::")).ok_or_else(not_found)?;
+    // See proc(5): the mount point is the fifth field, and the filesystem type follows the " - "
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let mount = mounts
+        .lines()
+        .find_map(|line| {
+            let (fields, rest) = line.split_once(" - ")?;
+            rest.starts_with("cgroup2 ").then(|| fields.split(' ').nth(4)).flatten()
+        })
+        .ok_or_else(not_found)?;
+    match own.trim_start_matches('/') {
+        "" => Ok(mount.into()),
+        own => Ok(Path::new(mount).join(own)),
+    }
+}
+
+/// Parses a number of CPUs for --cgroup-cpu-max, such as 2 or 0.5.
+pub fn parse_cpus(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => Err(format!("invalid number of CPUs: {}", s)),
+    }
+}
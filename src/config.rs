@@ -0,0 +1,260 @@
+//! Default options from the config file and XTEMP_OPTS, which are put ahead of the command line
+//! as if they'd been given there, except for any that the command line overrides.
+
+use clap::parser::ValueSource;
+use clap::{Arg, Command, Id};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+use crate::{Result, XtempError};
+
+/// Returns the path of the config file: $XTEMP_CONFIG if set, otherwise
+/// $XDG_CONFIG_HOME/xtemp/config.toml (or ~/.config/xtemp/config.toml).
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("XTEMP_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("xtemp").join("config.toml"))
+}
+
+/// Converts a config table into the equivalent command-line options, one list of words for each.
+/// Keys are long option names (e.g. `batch-size = 100`, `keep-newlines = true`) and arrays
+/// repeat the option.
+fn table_to_args(table: &Table) -> Result<Vec<Vec<String>>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            _ => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(vec![format!("--{}", key)]),
+                Value::Boolean(false) => {}
+                Value::String(s) => args.push(vec![format!("--{}={}", key, s)]),
+                Value::Integer(n) => args.push(vec![format!("--{}={}", key, n)]),
+                Value::Float(n) => args.push(vec![format!("--{}={}", key, n)]),
+                _ => {
+                    return Err(XtempError::InvalidConfig(format!(
+                        "unsupported value for '{}'",
+                        key
+                    )))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Reads default options from the config file, if there is one.
+fn config_file_args() -> Result<Vec<Vec<String>>> {
+    let Some(path) = config_path() else {
+        return Ok(Vec::new());
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(XtempError::InvalidConfig(format!("{}: {}", path.display(), e)))
+        }
+    };
+    let table: Table = contents
+        .parse()
+        .map_err(|e| XtempError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+    table_to_args(&table)
+}
+
+/// Reads default options from the XTEMP_OPTS environment variable, split like shell words and
+/// then into options like `table_to_args`.
+fn env_args(cmd: &Command) -> Result<Vec<Vec<String>>> {
+    let words = match env::var("XTEMP_OPTS") {
+        Ok(opts) => shlex::split(&opts)
+            .ok_or_else(|| XtempError::InvalidConfig("could not parse XTEMP_OPTS".into()))?,
+        Err(env::VarError::NotPresent) => return Ok(Vec::new()),
+        Err(e) => return Err(XtempError::InvalidConfig(format!("XTEMP_OPTS: {}", e))),
+    };
+    Ok(split_options(cmd, words))
+}
+
+/// Groups words into options, each with its value if that's the next word.
+fn split_options(cmd: &Command, words: Vec<String>) -> Vec<Vec<String>> {
+    let mut args: Vec<Vec<String>> = Vec::new();
+    let mut words = words.into_iter();
+    while let Some(word) = words.next() {
+        if word == "--" {
+            args.push(std::iter::once(word).chain(words.by_ref()).collect());
+            break;
+        }
+        // The option's value is the next word unless it's part of this one
+        let takes_value = option_args(cmd, &word).last().is_some_and(|arg| {
+            let attached = if word.starts_with("--") {
+                word.contains('=')
+            } else {
+                !arg.get_short().is_some_and(|c| word.ends_with(c))
+            };
+            arg.get_action().takes_values() && !arg.is_require_equals_set() && !attached
+        });
+        let mut option = vec![word];
+        if takes_value {
+            option.extend(words.next());
+        }
+        args.push(option);
+    }
+    args
+}
+
+/// Returns the options that a word such as `--batch-size=5` or `-0n5` gives.
+fn option_args<'a>(cmd: &'a Command, word: &str) -> Vec<&'a Arg> {
+    if let Some(long) = word.strip_prefix("--") {
+        let name = long.split_once('=').map_or(long, |(name, _)| name);
+        return cmd.get_arguments().filter(|arg| arg.get_long() == Some(name)).collect();
+    }
+    let mut args = Vec::new();
+    for c in word.strip_prefix('-').unwrap_or_default().chars() {
+        let Some(arg) = cmd.get_arguments().find(|arg| arg.get_short() == Some(c)) else {
+            break;
+        };
+        args.push(arg);
+        // The rest of the word is its value
+        if arg.get_action().takes_values() {
+            break;
+        }
+    }
+    args
+}
+
+/// Whether a default for `arg` gives way to the options in `given`, by being one of them or
+/// conflicting with one of them.
+fn overridden(cmd: &Command, arg: &Arg, given: &[&Arg]) -> bool {
+    let conflicts = |a: &Arg, b: &Arg| {
+        cmd.get_arg_conflicts_with(a).iter().any(|c| c.get_id() == b.get_id())
+    };
+    given.iter().any(|g| g.get_id() == arg.get_id() || conflicts(arg, g) || conflicts(g, arg))
+}
+
+/// Drops the defaults that `given` overrides, adding the options of those that are left to it.
+fn keep_defaults<'a>(
+    cmd: &'a Command,
+    defaults: Vec<Vec<String>>,
+    given: &mut Vec<&'a Arg>,
+) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut added = Vec::new();
+    for option in defaults {
+        let args = option_args(cmd, &option[0]);
+        if !args.iter().any(|arg| overridden(cmd, arg, given)) {
+            added.extend(args);
+            kept.extend(option);
+        }
+    }
+    given.extend(added);
+    kept
+}
+
+/// Returns the options given on the command line, or none if it doesn't parse: that's
+/// reported once the defaults are in place.
+fn given_args<'a>(cmd: &'a Command, argv: &[OsString]) -> Vec<&'a Arg> {
+    let Ok(matches) = cmd.clone().try_get_matches_from(argv) else {
+        return Vec::new();
+    };
+    let given = |arg: &&Arg| {
+        let id: &Id = arg.get_id();
+        matches.value_source(id.as_str()) == Some(ValueSource::CommandLine)
+    };
+    cmd.get_arguments().filter(given).collect()
+}
+
+/// Returns the process arguments with defaults from the config file and XTEMP_OPTS inserted
+/// ahead of the actual command-line arguments. Rather than adding to an option that's given
+/// on the command line, which couldn't undo a default such as `keep-newlines = true` or clear
+/// an array, a default for it is left out, as is one that conflicts with it. XTEMP_OPTS
+/// overrides the config file in the same way.
+pub fn args_with_defaults(cmd: &Command) -> Result<Vec<OsString>> {
+    let argv: Vec<OsString> = env::args_os().collect();
+    let mut given = given_args(cmd, &argv);
+    let env_args = keep_defaults(cmd, env_args(cmd)?, &mut given);
+    let config_args = keep_defaults(cmd, config_file_args()?, &mut given);
+    let (argv0, argv) = argv.split_at(argv.len().min(1));
+    let mut args: Vec<OsString> = argv0.to_vec();
+    args.extend(config_args.into_iter().map(OsString::from));
+    args.extend(env_args.into_iter().map(OsString::from));
+    args.extend(argv.iter().cloned());
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ArgAction;
+
+    fn command() -> Command {
+        Command::new("xtemp")
+            .arg(Arg::new("null").short('0').long("null").action(ArgAction::SetTrue))
+            .arg(Arg::new("batch-size").short('n').long("batch-size"))
+            .arg(Arg::new("opt").long("opt"))
+            .arg(Arg::new("lines").long("lines").action(ArgAction::SetTrue).conflicts_with("null"))
+            .arg(Arg::new("command").num_args(0..).trailing_var_arg(true))
+    }
+
+    fn split(words: &[&str]) -> Vec<Vec<String>> {
+        split_options(&command(), words.iter().map(|w| w.to_string()).collect())
+    }
+
+    fn ids<'a>(args: &[&'a Arg]) -> Vec<&'a str> {
+        args.iter().map(|arg| arg.get_id().as_str()).collect()
+    }
+
+    #[test]
+    fn splits_values_from_the_next_word_only_when_not_attached() {
+        assert_eq!(split(&["-n5", "--null"]), [vec!["-n5"], vec!["--null"]]);
+        assert_eq!(split(&["-n", "5", "--null"]), [vec!["-n", "5"], vec!["--null"]]);
+        assert_eq!(split(&["--opt=val", "-0"]), [vec!["--opt=val"], vec!["-0"]]);
+        assert_eq!(split(&["--opt", "val", "-0"]), [vec!["--opt", "val"], vec!["-0"]]);
+        assert_eq!(split(&["-0n5", "-0n", "5"]), [vec!["-0n5"], vec!["-0n", "5"]]);
+    }
+
+    #[test]
+    fn keeps_everything_after_double_dash_together() {
+        assert_eq!(split(&["-0", "--", "-n", "5"]), [vec!["-0"], vec!["--", "-n", "5"]]);
+    }
+
+    #[test]
+    fn finds_the_options_in_a_word() {
+        let cmd = command();
+        assert_eq!(ids(&option_args(&cmd, "-n5")), ["batch-size"]);
+        assert_eq!(ids(&option_args(&cmd, "--opt=val")), ["opt"]);
+        assert_eq!(ids(&option_args(&cmd, "-0n5")), ["null", "batch-size"]);
+        assert_eq!(ids(&option_args(&cmd, "-n0")), ["batch-size"]);
+        assert!(option_args(&cmd, "--").is_empty());
+    }
+
+    #[test]
+    fn drops_defaults_that_are_given_or_conflict() {
+        let cmd = command();
+        let defaults = split(&["-n5", "--null", "--opt", "val"]);
+        let lines = cmd.get_arguments().find(|arg| arg.get_id() == "lines").unwrap();
+        let batch_size = cmd.get_arguments().find(|arg| arg.get_id() == "batch-size").unwrap();
+        let mut given = vec![lines, batch_size];
+        assert_eq!(keep_defaults(&cmd, defaults, &mut given), ["--opt", "val"]);
+        assert_eq!(ids(&given), ["lines", "batch-size", "opt"]);
+    }
+
+    #[test]
+    fn later_defaults_give_way_to_earlier_ones() {
+        let cmd = command();
+        let mut given = given_args(&cmd, &["xtemp".into(), "--lines".into()]);
+        let env = keep_defaults(&cmd, split(&["-n", "5"]), &mut given);
+        let config = split(&["--batch-size=10", "-0", "--opt=val"]);
+        let config = keep_defaults(&cmd, config, &mut given);
+        assert_eq!(env, ["-n", "5"]);
+        assert_eq!(config, ["--opt=val"]);
+    }
+}
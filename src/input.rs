@@ -0,0 +1,706 @@
+//! Reading input records and grouping them into batches. Records are read on separate threads
+//! so that batches can be dispatched while more input is still arriving.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::process::Stdio;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transcode::Transcoder;
+use crate::{log, rejects, status, sys, Result, XtempError};
+
+/// How often to check for more input at the end of a file with --follow
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often to check whether xtemp has been asked to stop while waiting for input
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many records may be read ahead of the batch being assembled
+const READ_AHEAD: usize = 1024;
+
+/// Returns a record without its delimiter: NUL with -0, otherwise "\n" or "\r\n".
+pub fn strip_delimiter(record: &str, null: bool) -> &str {
+    if null {
+        return record.strip_suffix('\0').unwrap_or(record);
+    }
+    let record = record.strip_suffix('\n').unwrap_or(record);
+    record.strip_suffix('\r').unwrap_or(record)
+}
+
+/// What to do with a record larger than --max-record-bytes
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Oversized {
+    /// Stop with an error
+    Error,
+    /// Leave the record out
+    Skip,
+    /// Keep the start of the record, up to the limit
+    Truncate,
+}
+
+/// Where records come from, with --arg and --arg-file
+pub enum Source {
+    /// Records given literally, already delimited
+    Records(Vec<String>),
+    File(std::fs::File),
+    Stdin,
+}
+
+/// How records from more than one source are combined
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Merge {
+    /// All of each source in turn
+    Concat,
+    /// One record from each source in turn, until they're all exhausted
+    Roundrobin,
+}
+
+/// How records are delimited, and how large they may be
+#[derive(Clone, Copy, Debug)]
+pub struct RecordFormat {
+    /// Whether records are terminated by NUL rather than newline
+    pub null: bool,
+    /// Limit on the size of a record, not counting its delimiter
+    pub max_bytes: Option<u64>,
+    pub oversized: Oversized,
+    /// Encoding to transcode records from, if not UTF-8
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+}
+
+/// Limits on the size of a batch
+pub struct BatchLimits {
+    /// Number of records, or with `group_by` number of groups
+    pub records: usize,
+    pub bytes: Option<u64>,
+    /// Whether a newline is written after each record, which counts toward `bytes`
+    pub newline: bool,
+    pub group_by: Option<GroupBy>,
+    /// Room for arguments when records are passed as arguments themselves
+    pub arg_budget: Option<ArgBudget>,
+}
+
+/// Room in argv for a batch whose records are passed as arguments, with --paths or -I
+pub struct ArgBudget {
+    pub bytes: usize,
+    /// What each record's other arguments cost, such as the path of its tempfile
+    pub per_record: usize,
+    /// How many times each record is passed
+    pub slots: usize,
+    /// Words that the records are joined into, with --paths and {files:SEP}
+    pub joined: Vec<Joined>,
+}
+
+/// A word that a batch's arguments are all joined into, which besides counting toward ARG_MAX
+/// is limited in size by itself
+pub struct Joined {
+    /// How many bytes of arguments and separators fit, counting one separator for each argument
+    pub room: usize,
+    pub sep: usize,
+}
+
+/// How records are grouped for --group-by: by one of their fields, separated by `colsep` or
+/// otherwise by whitespace
+pub struct GroupBy {
+    /// Which field to group by, counting from 1
+    pub field: usize,
+    pub colsep: Option<String>,
+}
+
+impl GroupBy {
+    /// Returns a record's key, which is empty if it doesn't have enough fields.
+    fn key<'a>(&self, record: &'a str) -> &'a str {
+        let field = match self.colsep {
+            Some(ref sep) => record.split(sep.as_str()).nth(self.field - 1),
+            None => record.split_whitespace().nth(self.field - 1),
+        };
+        field.unwrap_or("")
+    }
+}
+
+/// Takes the `field`th field, counting from 1, out of a record for --stdin-field. Returns the
+/// field and the record without it, with the remaining fields joined by `colsep` (default: a space)
+/// and the delimiter kept. A record without enough fields gives an empty field.
+pub fn take_field(
+    record: &str,
+    field: usize,
+    colsep: Option<&str>,
+    null: bool,
+) -> (String, String) {
+    let stripped = strip_delimiter(record, null);
+    let delimiter = &record[stripped.len()..];
+    let mut fields: Vec<&str> = match colsep {
+        Some(sep) => stripped.split(sep).collect(),
+        None => stripped.split_whitespace().collect(),
+    };
+    if field > fields.len() {
+        return (String::new(), record.to_owned());
+    }
+    let taken = fields.remove(field - 1).to_owned();
+    (taken, fields.join(colsep.unwrap_or(" ")) + delimiter)
+}
+
+enum Next {
+    Record(String),
+    /// --flush-interval passed without a new record
+    Timeout,
+    End,
+}
+
+pub struct Input {
+    records: Receiver<Result<String>>,
+    /// A record that didn't fit in the previous batch, or an error that came after it
+    pending: Option<Result<String>>,
+    flush_interval: Option<Duration>,
+    format: RecordFormat,
+    /// The socket being served, if any
+    socket: Option<PathBuf>,
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Reads a record and its delimiter into `record` like `read_until`, but keeps at most `max`
+/// bytes of the record itself. Returns the number of bytes read, which is 0 at the end of input,
+/// and whether the record was larger than `max`. Unless `finish` is set, reading stops as soon
+/// as the record turns out to be too large. A too large record is also passed in full to `spill`
+/// if given, as it's read. `oversized` says whether the start of the record, already in `record`
+/// from an earlier call, was too large.
+fn read_bounded(
+    reader: &mut impl BufRead,
+    delimiter: u8,
+    max: u64,
+    finish: bool,
+    record: &mut Vec<u8>,
+    mut oversized: bool,
+    mut spill: Option<&mut rejects::Writer>,
+) -> io::Result<(usize, bool)> {
+    let mut read = 0;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            return Ok((read, oversized));
+        }
+        let (used, content, done) = match buf.iter().position(|&b| b == delimiter) {
+            Some(i) => (i + 1, &buf[..i], true),
+            None => (buf.len(), buf, false),
+        };
+        let room = (max as usize).saturating_sub(record.len());
+        let (kept, rest) = content.split_at(content.len().min(room));
+        record.extend_from_slice(kept);
+        if let Some(spill) = spill.as_mut().filter(|_| !rest.is_empty()) {
+            if !oversized {
+                spill.add(record);
+            }
+            spill.add(rest);
+        }
+        oversized |= !rest.is_empty();
+        if done {
+            record.push(delimiter);
+        }
+        reader.consume(used);
+        read += used;
+        if done || (oversized && !finish) {
+            return Ok((read, oversized));
+        }
+    }
+}
+
+/// Converts a record to a string. A truncated record may end partway through a character, which
+/// is dropped.
+fn record_to_string(mut record: Vec<u8>, delimiter: u8, truncated: bool) -> Result<String> {
+    if truncated {
+        let delimited = record.last() == Some(&delimiter);
+        let content = &record[..record.len() - delimited as usize];
+        if let Err(e) = std::str::from_utf8(content) {
+            if e.error_len().is_none() {
+                record.truncate(e.valid_up_to());
+                if delimited {
+                    record.push(delimiter);
+                }
+            }
+        }
+    }
+    String::from_utf8(record)
+        .map_err(|e| XtempError::InvalidUtf8(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Sends records from `reader` until it's exhausted (or, with `follow`, forever), returning
+/// false if the receiving end has gone away.
+fn send_records(
+    mut reader: impl BufRead,
+    format: RecordFormat,
+    follow: bool,
+    tx: &SyncSender<Result<String>>,
+) -> bool {
+    let delimiter = if format.null { b'\0' } else { b'\n' };
+    // Records that are skipped for being too large go in the --rejects file as they're read
+    let spill = format.oversized == Oversized::Skip && rejects::enabled();
+    // With `follow`, the start of a record whose delimiter hasn't been written yet
+    let mut record = Vec::new();
+    let mut oversized = false;
+    let mut rejected = None;
+    loop {
+        if spill && rejected.is_none() {
+            rejected = Some(rejects::Writer::new(format.null));
+        }
+        let read = match format.max_bytes {
+            Some(max) => {
+                let finish = format.oversized != Oversized::Error;
+                let (record, spill) = (&mut record, rejected.as_mut());
+                read_bounded(&mut reader, delimiter, max, finish, record, oversized, spill)
+            }
+            None => reader.read_until(delimiter, &mut record).map(|n| (n, false)),
+        };
+        let result = match read {
+            Ok((0, _)) if follow => {
+                thread::sleep(FOLLOW_POLL_INTERVAL);
+                continue;
+            }
+            Ok((0, _)) => return true,
+            Ok((_, true)) if format.oversized == Oversized::Error => {
+                Err(XtempError::RecordTooLong(format.max_bytes.unwrap_or(0)))
+            }
+            // The rest of the line may still be on its way
+            Ok((_, too_large)) if follow && record.last() != Some(&delimiter) => {
+                oversized = too_large;
+                continue;
+            }
+            Ok((_, true)) => match format.oversized {
+                Oversized::Skip => {
+                    log::info("skipped a record larger than --max-record-bytes");
+                    if let Some(rejected) = rejected.take() {
+                        rejected.finish();
+                    }
+                    record.clear();
+                    oversized = false;
+                    continue;
+                }
+                _ => {
+                    log::info("truncated a record larger than --max-record-bytes");
+                    record_to_string(std::mem::take(&mut record), delimiter, true)
+                }
+            },
+            Ok((_, false)) => record_to_string(std::mem::take(&mut record), delimiter, false),
+            Err(e) => Err(XtempError::InvalidUtf8(e)),
+        };
+        oversized = false;
+        let failed = result.is_err();
+        if tx.send(result).is_err() {
+            return false;
+        }
+        if failed {
+            return true;
+        }
+    }
+}
+
+/// Sends records from `reader` like `send_records`, transcoding them to UTF-8 first if needed.
+fn send_input(
+    reader: impl BufRead,
+    format: RecordFormat,
+    follow: bool,
+    tx: &SyncSender<Result<String>>,
+) -> bool {
+    match format.encoding {
+        Some(encoding) => send_records(Transcoder::new(reader, encoding), format, follow, tx),
+        None => send_records(reader, format, follow, tx),
+    }
+}
+
+/// Sends the records from a source like `send_input`. With `follow`, stdin is followed if it's a
+/// regular file.
+fn send_source(
+    source: Source,
+    format: RecordFormat,
+    follow: bool,
+    tx: &SyncSender<Result<String>>,
+) -> bool {
+    match source {
+        Source::Records(records) => records.into_iter().all(|record| tx.send(Ok(record)).is_ok()),
+        Source::File(file) => send_input(io::BufReader::new(file), format, false, tx),
+        Source::Stdin => {
+            let follow = follow && sys::stdin_is_file();
+            send_input(io::stdin().lock(), format, follow, tx)
+        }
+    }
+}
+
+/// Shows the first line of a record on the terminal and waits for the answer to whether to pick
+/// it: one of y, n, a or q. The end of the terminal's input counts as q.
+#[cfg(unix)]
+fn ask(tty: &mut std::fs::File, record: &str) -> io::Result<u8> {
+    let mut lines = record.lines();
+    let first = lines.next().unwrap_or("");
+    let more = if lines.next().is_some() { " ..." } else { "" };
+    loop {
+        write!(tty, "{}{} [y,n,a,q]? ", first, more)?;
+        let key = sys::read_key(tty)?.map(|key| key.to_ascii_lowercase());
+        if key != Some(b'\n') {
+            writeln!(tty)?;
+        }
+        match key {
+            Some(key @ (b'y' | b'n' | b'a' | b'q')) => return Ok(key),
+            None => return Ok(b'q'),
+            Some(_) => {}
+        }
+    }
+}
+
+impl Input {
+    /// Starts reading from several sources, combining their records as `merge` says.
+    pub fn sources(
+        sources: Vec<Source>,
+        merge: Merge,
+        format: RecordFormat,
+        follow: bool,
+        flush_interval: Option<Duration>,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        match merge {
+            Merge::Concat => {
+                thread::spawn(move || {
+                    for source in sources {
+                        if !send_source(source, format, follow, &tx) {
+                            return;
+                        }
+                    }
+                });
+            }
+            Merge::Roundrobin => {
+                let mut receivers: Vec<_> = sources
+                    .into_iter()
+                    .map(|source| {
+                        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+                        thread::spawn(move || send_source(source, format, follow, &tx));
+                        rx
+                    })
+                    .collect();
+                thread::spawn(move || {
+                    let mut i = 0;
+                    while !receivers.is_empty() {
+                        i %= receivers.len();
+                        match receivers[i].recv() {
+                            Ok(record) => {
+                                if tx.send(record).is_err() {
+                                    return;
+                                }
+                                i += 1;
+                            }
+                            // This source is exhausted, so carry on with the rest
+                            Err(_) => {
+                                receivers.remove(i);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        Input { records: rx, pending: None, flush_interval, format, socket: None }
+    }
+
+    /// Starts reading stdin. With `follow`, reaching the end of a regular file means waiting for
+    /// it to grow rather than the end of input.
+    pub fn stdin(format: RecordFormat, follow: bool, flush_interval: Option<Duration>) -> Self {
+        let follow = follow && sys::stdin_is_file();
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        thread::spawn(move || send_input(io::stdin().lock(), format, follow, &tx));
+        Input { records: rx, pending: None, flush_interval, format, socket: None }
+    }
+
+    /// Runs a shell command and reads its stdout. Its exiting non-zero is reported as an error
+    /// after the last of its output. If input stops being read first, the command is killed.
+    pub fn command(
+        command: &str,
+        format: RecordFormat,
+        flush_interval: Option<Duration>,
+    ) -> Result<Self> {
+        let mut child = sys::shell_command(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| XtempError::SubprocessFailed(format!("input command: {}", e)))?;
+        let stdout = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        thread::spawn(move || {
+            if !send_input(stdout, format, false, &tx) {
+                let _ = child.kill();
+            }
+            match child.wait() {
+                Ok(status) if !status.success() => {
+                    let _ = tx.send(Err(XtempError::SubprocessFailed(format!(
+                        "input command exited with code {}",
+                        sys::exit_code(status)
+                    ))));
+                }
+                _ => {}
+            }
+        });
+        Ok(Input { records: rx, pending: None, flush_interval, format, socket: None })
+    }
+
+    /// Creates a Unix socket at `path` and starts accepting connections on it, reading records
+    /// from each of them concurrently. Input never ends. The socket is removed when the input is
+    /// dropped.
+    #[cfg(unix)]
+    pub fn socket(
+        path: &std::path::Path,
+        format: RecordFormat,
+        flush_interval: Option<Duration>,
+    ) -> io::Result<Self> {
+        let listener = sys::bind_socket(path)?;
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                // A failed connection is the writer's problem, not a reason to stop serving
+                let Ok(stream) = stream else { continue };
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    send_input(io::BufReader::new(stream), format, false, &tx);
+                });
+            }
+        });
+        Ok(Input {
+            records: rx,
+            pending: None,
+            flush_interval,
+            format,
+            socket: Some(path.into()),
+        })
+    }
+
+    /// Passes the records through a shell command, which reads them on its stdin and writes the
+    /// records to use instead on its stdout, with the same delimiter.
+    pub fn map(mut self, command: &str) -> Result<Self> {
+        let mut child = sys::shell_command(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| XtempError::SubprocessFailed(format!("map command: {}", e)))?;
+        let mut stdin = io::BufWriter::new(child.stdin.take().expect("stdin is piped"));
+        let stdout = io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let (_, unused) = mpsc::sync_channel(0);
+        let records = std::mem::replace(&mut self.records, unused);
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let delimiter = if self.format.null { '\0' } else { '\n' };
+
+        let error_tx = tx.clone();
+        thread::spawn(move || loop {
+            // Flush only once there's nothing more to write for now, so that the command sees
+            // each record promptly without a write for every one
+            let record = match records.try_recv() {
+                Ok(record) => record,
+                Err(TryRecvError::Empty) => match stdin.flush() {
+                    Ok(()) => match records.recv() {
+                        Ok(record) => record,
+                        Err(_) => return,
+                    },
+                    Err(_) => return,
+                },
+                Err(TryRecvError::Disconnected) => return,
+            };
+            let written = match record {
+                Ok(record) if record.ends_with(delimiter) => stdin.write_all(record.as_bytes()),
+                Ok(record) => write!(stdin, "{}{}", record, delimiter),
+                Err(e) => {
+                    let _ = error_tx.send(Err(e));
+                    return;
+                }
+            };
+            // The command exiting early is reported once it's been waited for
+            if written.is_err() {
+                return;
+            }
+        });
+
+        // The command's output is already transcoded
+        let format = RecordFormat { encoding: None, ..self.format };
+        thread::spawn(move || {
+            if send_records(stdout, format, false, &tx) {
+                if let Ok(status) = child.wait() {
+                    if !status.success() {
+                        let _ = tx.send(Err(XtempError::SubprocessFailed(format!(
+                            "map command exited with code {}",
+                            sys::exit_code(status)
+                        ))));
+                    }
+                }
+            }
+        });
+        self.records = rx;
+        Ok(self)
+    }
+
+    /// Passes on only the records, without their delimiters, that `keep` returns true for.
+    pub fn filter(mut self, mut keep: impl FnMut(&str) -> bool + Send + 'static) -> Self {
+        let (_, unused) = mpsc::sync_channel(0);
+        let records = std::mem::replace(&mut self.records, unused);
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let null = self.format.null;
+        thread::spawn(move || {
+            for record in records {
+                let kept = match record {
+                    Ok(ref r) => keep(strip_delimiter(r, null)),
+                    Err(_) => true,
+                };
+                if kept && tx.send(record).is_err() {
+                    return;
+                }
+            }
+        });
+        self.records = rx;
+        self
+    }
+
+    /// Asks on the terminal whether to use each record, for --pick, passing on only those that
+    /// are picked and adding those that aren't to the --rejects file. Answering "a" picks the
+    /// rest without asking, and "q" ends the input there.
+    #[cfg(unix)]
+    pub fn pick(mut self) -> Result<Self> {
+        let mut tty = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .map_err(|e| XtempError::FailedToOpen("/dev/tty".into(), e))?;
+        let (_, unused) = mpsc::sync_channel(0);
+        let records = std::mem::replace(&mut self.records, unused);
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let null = self.format.null;
+        thread::spawn(move || {
+            let mut all = false;
+            for record in records {
+                let picked = match record {
+                    Ok(ref r) if !all => match ask(&mut tty, strip_delimiter(r, null)) {
+                        Ok(b'y') => true,
+                        Ok(b'a') => {
+                            all = true;
+                            true
+                        }
+                        Ok(b'q') => return,
+                        Ok(_) => {
+                            rejects::add_logged(r.as_bytes(), null);
+                            false
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(XtempError::FailedToOpen("/dev/tty".into(), e)));
+                            return;
+                        }
+                    },
+                    _ => true,
+                };
+                if picked && tx.send(record).is_err() {
+                    return;
+                }
+            }
+        });
+        self.records = rx;
+        Ok(self)
+    }
+
+    /// Returns the next record. With `timeout`, gives up after --flush-interval, if set. Input
+    /// ends early once xtemp has been asked to stop.
+    fn next_record(&mut self, timeout: bool) -> Result<Next> {
+        if let Some(record) = self.pending.take() {
+            return record.map(Next::Record);
+        }
+        let deadline = self.flush_interval.filter(|_| timeout).map(|i| Instant::now() + i);
+        loop {
+            if status::draining() {
+                return Ok(Next::End);
+            }
+            let mut wait = DRAIN_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(Next::Timeout);
+                }
+                wait = wait.min(left);
+            }
+            match self.records.recv_timeout(wait) {
+                Ok(Ok(record)) => return Ok(Next::Record(record)),
+                Ok(Err(e)) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(Next::End),
+            }
+        }
+    }
+
+    /// Returns the records that have been read but not yet batched, without waiting for more.
+    pub fn take_buffered(&mut self) -> Vec<String> {
+        let pending = self.pending.take().and_then(Result::ok);
+        pending.into_iter().chain(self.records.try_iter().filter_map(Result::ok)).collect()
+    }
+
+    /// Reads the next batch of records, which is empty at the end of input. The batch is closed
+    /// when it reaches one of the limits, or when --flush-interval passes without a new record.
+    /// A record that exceeds the byte limit on its own still gets a batch to itself.
+    /// With `limits.group_by` each entry holds all of the batch's records with the same key,
+    /// still delimited, and the batch is only complete once a record with a new key arrives.
+    /// Returns the entries along with how many records each of them holds.
+    pub fn next_batch(&mut self, limits: &BatchLimits) -> Result<(Vec<String>, Vec<usize>)> {
+        let mut batch: Vec<String> = Vec::new();
+        let mut counts = Vec::new();
+        let mut groups: HashMap<String, usize> = HashMap::new();
+        let mut total = 0;
+        let mut arg_total = 0;
+        let mut joined_totals = vec![0; limits.arg_budget.as_ref().map_or(0, |b| b.joined.len())];
+        while limits.group_by.is_some() || batch.len() < limits.records {
+            // Only time out once there's a partial batch to dispatch
+            let record = match self.next_record(!batch.is_empty()) {
+                Ok(Next::Record(record)) => record,
+                Ok(Next::Timeout | Next::End) => break,
+                // Run the records that came before the error first
+                Err(e) if !batch.is_empty() => {
+                    self.pending = Some(Err(e));
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let stripped = strip_delimiter(&record, self.format.null);
+            total += stripped.len() as u64 + limits.newline as u64;
+            if let Some(ref budget) = limits.arg_budget {
+                arg_total += budget.per_record + budget.slots * sys::arg_cost(stripped.as_ref());
+                for (joined, joined_total) in budget.joined.iter().zip(&mut joined_totals) {
+                    *joined_total += stripped.len() + joined.sep;
+                    arg_total += stripped.len() + joined.sep;
+                }
+            }
+            let full = limits.bytes.is_some_and(|max| total > max)
+                || limits.arg_budget.as_ref().is_some_and(|budget| {
+                    arg_total > budget.bytes
+                        || budget.joined.iter().zip(&joined_totals).any(|(j, &n)| n > j.room)
+                });
+            if full && !batch.is_empty() {
+                self.pending = Some(Ok(record));
+                break;
+            }
+            if let Some(ref group_by) = limits.group_by {
+                let key = group_by.key(strip_delimiter(&record, self.format.null));
+                if let Some(&i) = groups.get(key) {
+                    batch[i].push_str(&record);
+                    counts[i] += 1;
+                    continue;
+                }
+                if batch.len() == limits.records {
+                    self.pending = Some(Ok(record));
+                    break;
+                }
+                groups.insert(key.to_owned(), batch.len());
+            }
+            batch.push(record);
+            counts.push(1);
+        }
+        Ok((batch, counts))
+    }
+}
@@ -0,0 +1,121 @@
+//! Descriptions of each batch for --results: job.json and job.env in the batch's directory, which
+//! say what the command was run with and, once it's finished, how it went.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::log::timestamp;
+use crate::progress::json_string;
+use crate::sys::Usage;
+
+pub struct Job {
+    pub batch: usize,
+    /// The command line as run, as text
+    pub command: Vec<String>,
+    /// Line numbers of the batch's first and last lines
+    pub lines: (usize, usize),
+    /// Paths and sizes of the files the command was given
+    pub tempfiles: Vec<(PathBuf, u64)>,
+    pub started: SystemTime,
+    /// Exit code and elapsed time, once the batch is over
+    pub finished: Option<(i32, Duration)>,
+    /// How many times starting the command had to be tried again
+    pub spawn_retries: u32,
+    /// What the command used, where that's known, once the batch is over
+    pub usage: Option<Usage>,
+}
+
+impl Job {
+    /// Writes job.json and job.env to `dir`, replacing any that are there.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::write(dir.join("job.json"), self.to_json())?;
+        fs::write(dir.join("job.env"), self.to_env())
+    }
+
+    fn to_json(&self) -> String {
+        let command: Vec<_> = self.command.iter().map(|arg| json_string(arg)).collect();
+        let tempfiles: Vec<_> = self
+            .tempfiles
+            .iter()
+            .map(|(path, size)| {
+                format!(r#"{{"path":{},"size":{}}}"#, json_string(&path.to_string_lossy()), size)
+            })
+            .collect();
+        let mut json = format!(
+            r#"{{"batch":{},"command":[{}],"first_line":{},"last_line":{},"tempfiles":[{}]"#,
+            self.batch,
+            command.join(","),
+            self.lines.0,
+            self.lines.1,
+            tempfiles.join(",")
+        );
+        let _ = write!(json, r#","started":"{}""#, timestamp(self.started));
+        if let Some((code, elapsed)) = self.finished {
+            let _ = write!(
+                json,
+                r#","finished":"{}","elapsed":{:.3},"exit_code":{},"spawn_retries":{}"#,
+                timestamp(self.started + elapsed),
+                elapsed.as_secs_f64(),
+                code,
+                self.spawn_retries
+            );
+        }
+        if let Some(ref usage) = self.usage {
+            let _ = write!(
+                json,
+                r#","max_rss_kb":{},"user_time":{:.3},"system_time":{:.3}"#,
+                usage.max_rss,
+                usage.user.as_secs_f64(),
+                usage.system.as_secs_f64()
+            );
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    /// Describes the job as shell variable assignments, which can be sourced.
+    fn to_env(&self) -> String {
+        let words = |words: &mut dyn Iterator<Item = &str>| {
+            quote(&words.map(quote).collect::<Vec<_>>().join(" "))
+        };
+        let mut env = String::new();
+        let _ = writeln!(env, "XTEMP_BATCH={}", self.batch);
+        let command = words(&mut self.command.iter().map(AsRef::as_ref));
+        let _ = writeln!(env, "XTEMP_COMMAND={}", command);
+        let _ = writeln!(env, "XTEMP_FIRST_LINE={}", self.lines.0);
+        let _ = writeln!(env, "XTEMP_LAST_LINE={}", self.lines.1);
+        let paths: Vec<_> = self.tempfiles.iter().map(|(path, _)| path.to_string_lossy()).collect();
+        let _ = writeln!(env, "XTEMP_TEMPFILES={}", words(&mut paths.iter().map(|p| p.as_ref())));
+        let _ = writeln!(env, "XTEMP_STARTED={}", timestamp(self.started));
+        if let Some((code, elapsed)) = self.finished {
+            let _ = writeln!(env, "XTEMP_EXIT_STATUS={}", code);
+            let _ = writeln!(env, "XTEMP_ELAPSED={:.3}", elapsed.as_secs_f64());
+            let _ = writeln!(env, "XTEMP_SPAWN_RETRIES={}", self.spawn_retries);
+        }
+        if let Some(ref usage) = self.usage {
+            let _ = writeln!(env, "XTEMP_MAX_RSS_KB={}", usage.max_rss);
+            let _ = writeln!(env, "XTEMP_USER_TIME={:.3}", usage.user.as_secs_f64());
+            let _ = writeln!(env, "XTEMP_SYSTEM_TIME={:.3}", usage.system.as_secs_f64());
+        }
+        env
+    }
+}
+
+/// Reads one of the numbers in a batch's job.env, such as XTEMP_EXIT_STATUS, which is missing
+/// if the batch didn't finish.
+pub fn read_number(dir: &Path, name: &str) -> Option<i64> {
+    let env = fs::read_to_string(dir.join("job.env")).ok()?;
+    env.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix('=')?.parse().ok())
+}
+
+/// Quotes a word for the shell, if it needs it.
+fn quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_owned();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
@@ -0,0 +1,230 @@
+//! Diagnostics on stderr, either as "xtemp: ..." text or, with --log-format json, as one JSON
+//! object per line with a timestamp and the batch number.
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::progress::json_string;
+use crate::status;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    /// Once per batch or invocation
+    Info,
+    /// Once per tempfile
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    /// The most detailed level that's shown, or None to show nothing
+    max_level: Option<Level>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets the format of diagnostics and how many of them to show: nothing with `quiet`, and more
+/// with each level of `verbose`. By default text only shows errors, while JSON, which is meant
+/// for machines, shows each batch as well.
+pub fn init(format: LogFormat, verbose: u8, quiet: bool) {
+    let max_level = match (quiet, verbose) {
+        (true, _) => None,
+        (false, 0) if format == LogFormat::Text => Some(Level::Error),
+        (false, 0 | 1) => Some(Level::Info),
+        (false, _) => Some(Level::Debug),
+    };
+    let _ = LOGGER.set(Logger { format, max_level });
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger { format: LogFormat::Text, max_level: Some(Level::Error) })
+}
+
+pub fn error(msg: &str) {
+    log(Level::Error, msg);
+}
+
+pub fn info(msg: &str) {
+    log(Level::Info, msg);
+}
+
+pub fn debug(msg: &str) {
+    log(Level::Debug, msg);
+}
+
+/// Logs something the user explicitly asked for, such as the SIGUSR1 report, whatever the
+/// level.
+#[cfg(unix)]
+pub fn report(msg: &str) {
+    // The report says which batch it's on itself
+    write(Level::Info, msg, false);
+}
+
+/// Logs `msg` if `level` is shown. Errors read well enough on their own, but progress is easier
+/// to follow with batch numbers.
+fn log(level: Level, msg: &str) {
+    if logger().max_level.is_some_and(|max| level <= max) {
+        write(level, msg, level > Level::Error);
+    }
+}
+
+/// Writes an entry, in text prefixed with the batch number if `with_batch` is set.
+fn write(level: Level, msg: &str, with_batch: bool) {
+    let batch = status::current_batch();
+    let line = match logger().format {
+        LogFormat::Text if batch > 0 && with_batch => {
+            msg.lines().map(|line| format!("xtemp: batch {}: {}\n", batch, line)).collect()
+        }
+        LogFormat::Text => msg.lines().map(|line| format!("xtemp: {}\n", line)).collect(),
+        LogFormat::Json => {
+            let batch = match batch {
+                0 => String::new(),
+                batch => format!(r#","batch":{}"#, batch),
+            };
+            format!(
+                "{{\"time\":\"{}\",\"level\":\"{}\"{},\"message\":{}}}\n",
+                timestamp(SystemTime::now()),
+                level.name(),
+                batch,
+                json_string(msg)
+            )
+        }
+    };
+    // Write each entry in one go so that entries from different threads don't interleave
+    let _ = std::io::stderr().write_all(line.as_bytes());
+}
+
+/// Parses an RFC 3339 timestamp such as 2024-05-06T07:08:09Z or 2024-05-06T09:08+02:00, where
+/// the seconds are optional but the offset from UTC isn't.
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let fields = |s: &str, sep| -> Option<Vec<i64>> {
+        s.split(sep)
+            .map(|f| f.bytes().all(|b| b.is_ascii_digit()).then(|| f.parse().ok()).flatten())
+            .collect()
+    };
+    let rest = s.get(10..)?.strip_prefix(['T', 't', ' '])?;
+    let [year, month, day] = *fields(s.get(..10)?, '-')?.as_slice() else {
+        return None;
+    };
+    let (time, offset) = rest.split_at(rest.find(['Z', 'z', '+', '-'])?);
+    let (hour, minute, second) = match *fields(time, ':')?.as_slice() {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return None,
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => match *fields(&offset[1..], ':')?.as_slice() {
+            [hours, minutes] if offset.starts_with('-') => -(hours * 3600 + minutes * 60),
+            [hours, minutes] => hours * 3600 + minutes * 60,
+            _ => return None,
+        },
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=month_days).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // Days since the epoch from the civil date (Howard Hinnant's days_from_civil)
+    let y = year - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(secs.try_into().ok()?))
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC, with milliseconds.
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Convert days since the epoch to a civil date (Howard Hinnant's days_from_civil, reversed)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn secs(s: &str) -> Option<u64> {
+        parse_timestamp(s).map(|t| t.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn parses_utc_and_offsets() {
+        assert_eq!(secs("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(secs("2024-05-06T07:08:09Z"), Some(1714979289));
+        assert_eq!(secs("2024-05-06T09:08:09+02:00"), Some(1714979289));
+        assert_eq!(secs("2024-05-06 07:08z"), Some(1714979280));
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        assert_eq!(secs("2024-05-06T07:08:60Z"), None);
+        assert_eq!(secs("2024-05-06T07:60:00Z"), None);
+        assert_eq!(secs("2024-05-06T24:00:00Z"), None);
+    }
+
+    #[test]
+    fn checks_days_against_the_month() {
+        assert_eq!(secs("2023-02-29T00:00Z"), None);
+        assert_eq!(secs("2023-02-31T00:00Z"), None);
+        assert_eq!(secs("2023-04-31T00:00Z"), None);
+        assert_eq!(secs("2024-02-29T00:00Z"), Some(1709164800));
+        assert_eq!(secs("2000-02-29T00:00Z"), Some(951782400));
+        assert_eq!(secs("1900-02-29T00:00Z"), None);
+    }
+
+    #[test]
+    fn round_trips_with_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1714979289);
+        assert_eq!(secs(&format!("{}Z", &timestamp(time)[..19])), Some(1714979289));
+    }
+}
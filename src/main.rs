@@ -1,18 +1,53 @@
-use clap::Parser;
+mod bench;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod config;
+mod input;
+mod job;
+mod log;
+mod man;
+mod pool;
+mod progress;
+mod rejects;
+#[cfg(unix)]
+mod remote;
+#[cfg(unix)]
+mod script;
+mod status;
+mod state;
+mod sys;
+mod template;
+mod transcode;
+mod worker;
+
+use clap::builder::{OsStringValueParser, TypedValueParser};
+use clap::{CommandFactory, Parser, Subcommand};
 use std::process;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::process::Stdio;
-use shell_escape::escape;
-use std::io::{self, BufRead, Write, Seek, SeekFrom};
+use std::process::{Child, ChildStdin, Stdio};
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::io::{self, Read, Write};
 use std::process::Command;
-use tempfile::NamedTempFile;
-use nix::sys::resource::{getrlimit, Resource};
+use std::time::{Duration, Instant, SystemTime};
+use clap_complete::Shell;
+use tempfile::TempDir;
 
 #[derive(Parser, Debug)]
 #[command(
     author,
     version,
     about,
+    args_override_self = true,
+    subcommand_value_name = "SUBCOMMAND",
+    disable_help_subcommand = true,
+    override_usage = "xtemp [OPTIONS] [COMMAND]...\n       xtemp <SUBCOMMAND>",
+    subcommand_help_heading = "Subcommands",
+    after_help = "Default options can be set in ~/.config/xtemp/config.toml (or the file named by \
+                  $XTEMP_CONFIG) and in $XTEMP_OPTS; options given on the command line override \
+                  them.",
     help_template = "\
 {before-help}{name} {version}
 {author-with-newline}
@@ -25,21 +60,630 @@ struct Args {
     #[arg(short = 'n', long)]
     batch_size: Option<usize>,
 
+    /// Maximum total bytes written to tempfiles per batch (e.g. 64K, 10M); the batch closes when
+    /// either this or the batch size is reached. A single larger line still gets its own batch
+    #[arg(short = 's', long, value_parser = parse_size)]
+    max_bytes: Option<u64>,
+
     /// Replacement string for tempfile arguments (if not specified, tempfiles are appended as trailing arguments)
     #[arg(short = 'J', long)]
-    replstr: Option<String>,
+    replstr: Option<OsString>,
+
+    /// Replacement string for the lines themselves, as with xargs -I: each argument that's
+    /// exactly REPLSTR is replaced by the batch's lines, one argument each
+    #[arg(
+        short = 'I',
+        long,
+        value_name = "REPLSTR",
+        conflicts_with_all = ["persistent", "group_by", "no_run"],
+    )]
+    record_replstr: Option<OsString>,
+
+    /// Escape for arguments that are meant literally: an argument that's STR followed by the -J
+    /// or -I replacement string is passed as the replacement string itself (e.g. with
+    /// --replstr-escape '\', an argument of \{} is passed as {})
+    #[arg(long, value_name = "STR")]
+    replstr_escape: Option<OsString>,
 
     /// Keep newlines when writing lines to tempfiles (default: strip newlines)
     #[arg(long)]
     keep_newlines: bool,
 
+    /// Fill in each tempfile from a template file instead of writing the line itself: {} is
+    /// replaced with the line and {1}, {2}, ... with its whitespace-separated fields
+    #[arg(long, value_name = "FILE", conflicts_with = "keep_newlines")]
+    template: Option<PathBuf>,
+
+    /// Write FMT to each tempfile instead of the line itself, with the same placeholders as
+    /// --template plus {line} for the line, {lineno} for its line number and {batch} for the
+    /// batch number
+    #[arg(long, value_name = "FMT", conflicts_with = "template")]
+    file_format: Option<String>,
+
+    /// Put the lines whose FIELDth field is the same into one tempfile, so that each tempfile in
+    /// a batch holds one key's lines (-n then limits the number of keys per batch)
+    #[arg(
+        long,
+        value_name = "FIELD",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["template", "file_format"],
+    )]
+    group_by: Option<u32>,
+
+    /// Separator between fields for --group-by and --stdin-field (default: whitespace)
+    #[arg(long, value_name = "SEP")]
+    colsep: Option<String>,
+
+    /// Take field N out of each line and write it to the command's stdin, one per line, rather
+    /// than to the tempfile, which gets the remaining fields
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["stdin_content", "group_by", "paths", "persistent", "double_buffer"],
+    )]
+    stdin_field: Option<u32>,
+
+    /// Input lines are terminated by NUL instead of newline, as with xargs -0
+    #[arg(short = '0', long)]
+    null: bool,
+
     /// Instead of passing all tempfiles as arguments, pass a single file containing a list of the
-    /// tempfile paths
+    /// tempfile paths (in place of any argument that's exactly {list}, unless -J is given)
     #[arg(short = 'l', long)]
     list: bool,
 
-    /// Command to execute with tempfile arguments
-    command: Vec<String>,
+    /// With -l, put at most N tempfiles in each list file, running the command once for each list
+    /// file in turn until one fails
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "list",
+        conflicts_with_all = [
+            "stdin_content", "stdin_field", "record_replstr", "env_name", "persistent", "then",
+            "double_buffer",
+        ],
+    )]
+    list_split: Option<u32>,
+
+    /// Instead of passing the tempfiles (or with -l the list file) as arguments, put their paths
+    /// in the command's environment variable VAR
+    #[arg(long, value_name = "VAR", conflicts_with = "replstr")]
+    env_name: Option<String>,
+
+    /// What to do when writing a line's tempfile fails (e.g. the disk is full): skip leaves the
+    /// line out of its batch, adding it to the --rejects file if given; retry tries again a few
+    /// times before giving up; abort stops with an error
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = pool::OnWriteError::Abort)]
+    on_write_error: pool::OnWriteError,
+
+    /// Append lines that are left out (by --pick, --oversized skip or --on-write-error skip) or
+    /// whose batch fails to FILE, as they were read, so that they can be run again later. With
+    /// --map they're the records it wrote, to be run again without it
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<PathBuf>,
+
+    /// How many times to try starting the command again when the system is short of processes
+    /// or memory, waiting twice as long each time
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    spawn_retries: u32,
+
+    /// How long to wait before trying to start the command again the first time (e.g. 100ms)
+    #[arg(long, value_name = "DURATION", default_value = "100ms", value_parser = parse_duration)]
+    spawn_retry_delay: Duration,
+
+    /// Separator between the paths in --env-name's variable
+    #[arg(long, value_name = "SEP", default_value = " ", requires = "env_name")]
+    env_separator: OsString,
+
+    /// Also write the batch's lines to the command's stdin, one per line (NUL-terminated with -0).
+    /// To pass only stdin, use -J with a replacement string that doesn't appear in the command
+    #[arg(long)]
+    stdin_content: bool,
+
+    /// Also copy each input line to stdout, unchanged, as its batch is dispatched
+    #[arg(long)]
+    tee: bool,
+
+    /// Keep starting batches after xtemp's stdout or stderr has been closed by whatever reads it
+    /// (e.g. head), instead of stopping quietly with status 141 as if killed by SIGPIPE
+    #[arg(long)]
+    ignore_sigpipe: bool,
+
+    /// Create new tempfiles for every batch instead of reusing the pool; each batch's files are
+    /// deleted after the following batch's invocation completes
+    #[arg(long)]
+    fresh_files: bool,
+
+    /// Read the next batch and write its tempfiles while the command runs on the current one,
+    /// using a second set of tempfiles. The current batch isn't finished off until the next one
+    /// is ready, so this suits input that's already available rather than a slow stream. It
+    /// can't be used with --adaptive, whose next batch size isn't known until the current one
+    /// is done
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "fresh_files", "paths", "persistent", "follow", "no_run", "cwd_tempdir", "adaptive",
+        ],
+    )]
+    double_buffer: bool,
+
+    /// Take each line as the path of an existing file and pass that to the command instead of a
+    /// tempfile, as xargs would
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "keep_newlines", "template", "file_format", "group_by", "fresh_files", "file_mode",
+            "file_group", "fsync", "write_threads", "cwd_tempdir", "no_run",
+        ],
+    )]
+    paths: bool,
+
+    /// Permissions for created tempfiles, in octal (default: 0600)
+    #[cfg(unix)]
+    #[arg(long, value_parser = parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Group (name or numeric ID) to own created tempfiles
+    #[cfg(unix)]
+    #[arg(long, value_parser = sys::parse_group)]
+    file_group: Option<u32>,
+
+    /// Fsync each tempfile and the directory containing it before executing the command
+    #[arg(long)]
+    fsync: bool,
+
+    /// Check that the tempfiles (or with --paths the input files) are left as they were by the
+    /// command and anything else run on the batch, failing if not
+    #[arg(long, conflicts_with = "no_run")]
+    verify: bool,
+
+    /// Write each batch's tempfiles using N threads
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+    )]
+    write_threads: u16,
+
+    /// Don't delete tempfiles when xtemp exits
+    #[arg(long)]
+    keep: bool,
+
+    /// Keep the tempfiles in memory as memfds instead of in a directory, passing them as
+    /// /proc/self/fd/N paths, which the command and anything it starts can open
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "paths", "fsync", "keep", "cwd_tempdir", "persistent", "ssh", "script", "no_run",
+        ],
+    )]
+    memfd: bool,
+
+    /// Hold a lock on FILE (created if need be) while running, waiting for any other xtemp
+    /// holding it to finish first, so that runs sharing files or directories don't overlap
+    #[arg(long, value_name = "FILE")]
+    lock: Option<PathBuf>,
+
+    /// Fail straight away if the --lock is held, rather than waiting for it
+    #[arg(long, requires = "lock")]
+    lock_nowait: bool,
+
+    /// Keep track in DIR (created if need be) of the records in batches that succeed, for
+    /// --skip-processed in later runs
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Skip records that a run with the same --state-dir has already processed successfully
+    #[arg(long, requires = "state_dir")]
+    skip_processed: bool,
+
+    /// Working directory for the command; {#} is replaced with the batch number
+    #[arg(long, value_name = "PATH", conflicts_with = "cwd_tempdir")]
+    cwd: Option<String>,
+
+    /// Create the tempfiles in a temporary directory of their own, run the command from within
+    /// it, and pass the tempfiles' names relative to it
+    #[arg(long)]
+    cwd_tempdir: bool,
+
+    /// Start the command with an empty environment (apart from the XTEMP_* variables)
+    #[arg(long)]
+    env_clear: bool,
+
+    /// Set an environment variable for the command (repeatable)
+    #[arg(
+        long,
+        value_name = "KEY=VAL",
+        value_parser = OsStringValueParser::new().try_map(parse_env_var),
+    )]
+    env: Vec<(OsString, OsString)>,
+
+    /// Pass only the environment variables whose names match GLOB (repeatable; * and ? are
+    /// wildcards) and clear the rest
+    #[arg(long, value_name = "GLOB")]
+    env_pass: Vec<String>,
+
+    /// Redirect the command's stdout to this file, created anew for each batch; {#} is replaced
+    /// with the batch number (e.g. out.{#}.txt)
+    #[arg(long, value_name = "PATTERN")]
+    stdout_file: Option<String>,
+
+    /// Keep a directory DIR/N for each batch N with the command's stdout and stderr (unless
+    /// redirected elsewhere), the batch's input, and job.json and job.env describing the batch.
+    /// The command is given the directory's path in XTEMP_JOB_DIR. See also replay
+    #[arg(long, value_name = "DIR", conflicts_with = "no_run")]
+    results: Option<PathBuf>,
+
+    /// The batch number and line number to start from when replaying a batch
+    #[arg(skip)]
+    replay_from: Option<(usize, usize)>,
+
+    /// Redirect the command's stderr to this file, like --stdout-file
+    #[arg(long, value_name = "PATTERN")]
+    stderr_file: Option<String>,
+
+    /// Shell command to run before each batch, with the batch's tempfiles as positional
+    /// parameters and XTEMP_BATCH (and XTEMP_LIST with -l) in its environment
+    #[arg(long, value_name = "CMD")]
+    pre_hook: Option<String>,
+
+    /// Shell command to run after each batch, like --pre-hook but with the command's exit code
+    /// in XTEMP_EXIT_STATUS as well
+    #[arg(long, value_name = "CMD")]
+    post_hook: Option<String>,
+
+    /// Shell command to run when the command exits non-zero, like --post-hook (e.g. to save the
+    /// batch's tempfiles for inspection)
+    #[arg(long, value_name = "CMD")]
+    on_fail: Option<String>,
+
+    /// Shell command to run instead when the command exits non-zero, like --post-hook; the batch
+    /// only fails if this fails too. Its output goes where the command's does
+    #[arg(long, value_name = "CMD")]
+    fallback: Option<String>,
+
+    /// Shell command to run as a second stage of each batch: the command's output is split into
+    /// lines like the input, each line written to a tempfile of its own, and those tempfiles
+    /// passed to CMD as positional parameters, in batches limited by -n and -s like the input's.
+    /// --stdout-file and --stderr-file then apply to CMD
+    #[arg(long, value_name = "CMD", conflicts_with_all = ["persistent", "fallback"])]
+    then: Option<String>,
+
+    /// Shell command to run after the command succeeds, with the batch's tempfiles (or with -l
+    /// the list file) as positional parameters and its output added to the command's
+    /// (repeatable; the commands run in turn, stopping at the first that fails)
+    #[arg(long = "and", value_name = "CMD")]
+    and: Vec<String>,
+
+    /// Run every --and command even if the command or an earlier one fails (the batch still
+    /// fails)
+    #[arg(long, requires = "and")]
+    and_keep_going: bool,
+
+    /// Run the command with its niceness adjusted by N, as with nice(1)
+    #[cfg(unix)]
+    #[arg(long, value_name = "N", allow_negative_numbers = true)]
+    nice: Option<i32>,
+
+    /// Run the command with the given I/O scheduling class (none, realtime, best-effort, idle, or
+    /// 0-3) and optionally level (0-7), as with ionice(1)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "CLASS[:LEVEL]", value_parser = sys::parse_ionice)]
+    ionice: Option<i32>,
+
+    /// Limit the address space of each command invocation (e.g. 512M, 4G)
+    #[cfg(unix)]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    limit_mem: Option<u64>,
+
+    /// Limit the CPU time of each command invocation, in seconds
+    #[cfg(unix)]
+    #[arg(long, value_name = "SECS")]
+    limit_cpu: Option<u64>,
+
+    /// Limit the size of files each command invocation may write (e.g. 100M)
+    #[cfg(unix)]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    limit_fsize: Option<u64>,
+
+    /// Run each command invocation in a cgroup of its own, killing anything left in it once the
+    /// command exits. If the cgroup can't be created, commands run without one
+    #[cfg(target_os = "linux")]
+    #[arg(long, conflicts_with_all = ["persistent", "ssh", "script"])]
+    cgroup: bool,
+
+    /// Create the --cgroup cgroups under DIR, such as a delegated cgroup, rather than under
+    /// xtemp's own. With limits, xtemp moves itself into a child of its own cgroup otherwise
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "DIR", requires = "cgroup")]
+    cgroup_parent: Option<PathBuf>,
+
+    /// Limit the memory of each --cgroup (e.g. 512M, 4G), counting everything the command starts
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "cgroup")]
+    cgroup_memory_max: Option<u64>,
+
+    /// Limit the CPU time of each --cgroup to the equivalent of N CPUs (e.g. 2, 0.5)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "N", value_parser = cgroup::parse_cpus, requires = "cgroup")]
+    cgroup_cpu_max: Option<f64>,
+
+    /// Run each command invocation in a session, and so a process group, of its own. Whatever
+    /// is left of the group once the command exits is sent SIGTERM, and SIGINT is passed on to
+    /// the group rather than stopping xtemp straight away
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "persistent")]
+    setsid: bool,
+
+    /// Start the command once and send it each batch's tempfile paths over its stdin, one per
+    /// line and followed by an empty line. After each batch it must print "done", or "done N"
+    /// with an exit code, on a line of its own; its other output is copied to stdout
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "replstr", "stdin_content", "stdout_file", "stderr_file", "env_name",
+        ],
+    )]
+    persistent: bool,
+
+    /// Instead of running a command, just write each batch's tempfiles, keeping them, and print
+    /// their paths (or with -l the list file's path) to stdout, followed by an empty line
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "command", "replstr", "stdin_content", "tee", "pre_hook", "post_hook", "on_fail",
+            "fallback", "then", "and",
+            "stdout_file", "stderr_file", "persistent", "env_name",
+        ],
+    )]
+    no_run: bool,
+
+    /// Instead of running anything, write a shell script with the command invocations to FILE
+    /// (default: stdout), keeping the tempfiles it refers to
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = [
+            "stdin_content", "stdin_field", "pre_hook", "post_hook", "on_fail", "fallback", "then",
+            "and",
+            "results", "env_clear", "env_pass", "nice",
+            "limit_mem", "limit_cpu", "limit_fsize", "setsid", "persistent", "no_run",
+            "double_buffer", "list_split",
+        ],
+    )]
+    #[cfg_attr(target_os = "linux", arg(conflicts_with = "ionice"))]
+    script: Option<Option<PathBuf>>,
+
+    /// Run the command on HOST (e.g. user@host) with ssh, copying each batch's tempfiles there
+    /// with scp first (repeatable; each host runs a batch at a time, alongside the others).
+    /// Hooks still run locally
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "HOST",
+        conflicts_with_all = [
+            "list", "cwd", "cwd_tempdir", "env_clear", "env_pass", "nice", "limit_mem",
+            "limit_cpu", "limit_fsize", "setsid", "persistent", "script", "env_name", "no_run",
+            "double_buffer",
+        ],
+    )]
+    #[cfg_attr(target_os = "linux", arg(conflicts_with = "ionice"))]
+    ssh: Vec<String>,
+
+    /// Keep reading when the end of input is reached, waiting for more to be appended, as with
+    /// tail -f (only applies when stdin is a regular file)
+    #[arg(long)]
+    follow: bool,
+
+    /// Read input from the output of shell command CMD instead of stdin. If CMD fails, so does
+    /// xtemp, once the batches of what it printed have run
+    #[arg(long, value_name = "CMD", conflicts_with_all = ["follow", "arg", "arg_file"])]
+    input_cmd: Option<String>,
+
+    /// Add the line REC to the input, ahead of what's read from stdin or --arg-file (repeatable)
+    #[arg(long = "arg", value_name = "REC")]
+    arg: Vec<String>,
+
+    /// Read input from FILE instead of stdin, or - for stdin (repeatable; files are read in the
+    /// order given)
+    #[arg(long, value_name = "FILE")]
+    arg_file: Vec<PathBuf>,
+
+    /// How to combine --arg, --arg-file and stdin: concat reads each of them in turn, and
+    /// roundrobin takes a line from each of them in turn
+    #[arg(long, value_name = "HOW", value_enum, default_value_t = input::Merge::Concat)]
+    merge: input::Merge,
+
+    /// Encoding of the input (e.g. latin1, utf-16le, shift_jis), which is converted to UTF-8
+    /// before being written to tempfiles
+    #[arg(long, value_name = "ENCODING", value_parser = transcode::parse_encoding)]
+    from_encoding: Option<&'static encoding_rs::Encoding>,
+
+    /// Shell command to pass the input through before batching it: it reads the lines on its
+    /// stdin and writes the lines to use instead to its stdout (e.g. "jq -c --unbuffered .id")
+    #[arg(long, value_name = "CMD")]
+    map: Option<String>,
+
+    /// Show each record (or its first line) on the terminal before batching it, and only use
+    /// those answered with y. Answer a to use the rest without asking, or q to stop reading input
+    #[cfg(unix)]
+    #[arg(long)]
+    pick: bool,
+
+    /// Limit on the size of an input line (e.g. 1M), which is enforced while it's being read
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_record_bytes: Option<u64>,
+
+    /// What to do with lines larger than --max-record-bytes
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = input::Oversized::Error)]
+    oversized: input::Oversized,
+
+    /// Dispatch a partial batch when no new lines have arrived for this long (e.g. 500ms, 5s)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    flush_interval: Option<Duration>,
+
+    /// Start with a small batch and adjust the batch size after each invocation to approach
+    /// --target-duration (the batch size given by -n, or its default, becomes the maximum)
+    #[arg(long)]
+    adaptive: bool,
+
+    /// Target wall time per invocation for --adaptive (e.g. 500ms, 30s, 5m)
+    #[arg(long, default_value = "30s", value_parser = parse_duration, requires = "adaptive")]
+    target_duration: Duration,
+
+    /// Stop starting batches at WHEN, a time such as 2024-05-06T07:00:00Z (or with an offset like
+    /// +02:00) or how long after starting (e.g. 50m), and exit with code 124
+    #[arg(long, value_name = "WHEN", value_parser = parse_deadline)]
+    deadline: Option<Deadline>,
+
+    /// Also terminate the command in flight when the --deadline passes
+    #[cfg(unix)]
+    #[arg(long, requires = "deadline")]
+    deadline_kill: bool,
+
+    /// Report each batch's command, hooks and cleanup on stderr; -vv reports each tempfile
+    /// written as well
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Don't print anything to stderr, not even errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Format of diagnostics on stderr; json reports each batch by default, as with -v
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = log::LogFormat::Text)]
+    log_format: log::LogFormat,
+
+    /// Write progress to file descriptor N, one JSON object per line for each event:
+    /// batch_started, batch_finished (with the command's peak memory and CPU time), failure and
+    /// totals
+    #[cfg(unix)]
+    #[arg(long, value_name = "N")]
+    progress_fd: Option<i32>,
+
+    /// Command to execute with tempfile arguments. An argument containing {files:SEP} gets the
+    /// tempfile arguments joined with SEP in its place, instead of them being passed separately
+    command: Vec<OsString>,
+
+    #[command(subcommand)]
+    subcommand: Option<Subcommands>,
+}
+
+impl Args {
+    /// The replacement string for tempfile arguments, which with -l defaults to {list} if the
+    /// command has it.
+    fn replstr(&self) -> Option<&OsStr> {
+        match self.replstr {
+            Some(ref replstr) => Some(replstr),
+            None if self.list && self.command.iter().any(|a| a == LIST_REPLSTR) => {
+                Some(LIST_REPLSTR.as_ref())
+            }
+            None => None,
+        }
+    }
+
+    /// Whether to leave tempfiles in place after exiting, which --script and --no-run need
+    fn keep_tempfiles(&self) -> bool {
+        #[cfg(unix)]
+        if self.script.is_some() {
+            return true;
+        }
+        self.keep || self.no_run
+    }
+
+    fn record_format(&self) -> input::RecordFormat {
+        input::RecordFormat {
+            null: self.null,
+            max_bytes: self.max_record_bytes,
+            oversized: self.oversized,
+            encoding: self.from_encoding.filter(|&encoding| encoding != encoding_rs::UTF_8),
+        }
+    }
+
+    /// Whether each batch needs tempfiles of its own, which --script and --no-run need
+    fn fresh_files(&self) -> bool {
+        #[cfg(unix)]
+        if self.script.is_some() {
+            return true;
+        }
+        self.fresh_files || self.no_run
+    }
+
+    /// Whether the tempfiles take up space in a filesystem, which they don't with --memfd
+    fn on_disk(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.memfd {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Print a man page in roff format to stdout
+    Man,
+    /// Listen on a Unix socket and run the command on batches of the lines written to it by any
+    /// number of clients, until interrupted. Options for batching and running the command go
+    /// before the subcommand
+    #[cfg(unix)]
+    #[command(override_usage = "xtemp [OPTIONS] serve --socket <PATH> [COMMAND]...")]
+    Serve {
+        /// Path of the socket to create
+        #[arg(long, value_name = "PATH")]
+        socket: PathBuf,
+
+        /// Command to execute with tempfile arguments
+        #[arg(required = true)]
+        command: Vec<OsString>,
+    },
+    /// Run the batches recorded by an earlier run with --results again, with the same options
+    /// and each batch's recorded input
+    Replay {
+        /// The earlier run's --results directory
+        #[arg(long, value_name = "DIR")]
+        results: PathBuf,
+
+        /// Only replay the batches that failed or didn't finish
+        #[arg(long)]
+        only_failed: bool,
+    },
+    /// Time runs of xtemp on generated input with different batch sizes, places to keep the
+    /// tempfiles and ways of writing them, and print a table comparing them
+    Bench {
+        /// Number of lines to generate
+        #[arg(long, value_name = "N", default_value_t = 10_000)]
+        records: usize,
+
+        /// Size of each line, not counting its newline (e.g. 100, 4K)
+        #[arg(long, value_name = "SIZE", default_value = "100", value_parser = parse_size)]
+        record_size: u64,
+
+        /// Batch sizes to try, separated by commas
+        #[arg(long, value_name = "N,...", value_delimiter = ',', default_value = "10,100,1000")]
+        batch_sizes: Vec<usize>,
+
+        /// Directory to put the tempfiles in (repeatable; default: the system temporary
+        /// directory, and /dev/shm if there is one). On Linux, memfds are tried as well
+        #[arg(long, value_name = "DIR")]
+        dir: Vec<PathBuf>,
+
+        /// Command to run on each batch
+        #[arg(default_value = "true")]
+        command: Vec<OsString>,
+    },
 }
 
 #[derive(Debug)]
@@ -48,6 +692,38 @@ pub enum XtempError {
     FailedToWrite(std::io::Error),
     SubprocessFailed(String),
     MissingCommand,
+    ArgsTooLong(usize, usize),
+    InvalidConfig(String),
+    FailedToOpen(String, std::io::Error),
+    /// Stopped by SIGTERM after the given number of batches
+    Terminated(usize),
+    /// An input record exceeded --max-record-bytes
+    RecordTooLong(u64),
+    /// Directory, bytes needed and bytes available
+    NoSpace(PathBuf, u64, u64),
+    /// A tempfile changed while its batch was running, with --verify
+    Modified(PathBuf),
+    /// The --lock file was held by someone else, with --lock-nowait
+    Locked(PathBuf),
+    /// Whatever reads xtemp's output has gone away
+    OutputClosed,
+    /// Stopped at the --deadline after the given number of batches
+    DeadlineReached(usize),
+}
+
+impl XtempError {
+    /// The exit code to report the error with: 143 (as if killed by SIGTERM) when stopped by
+    /// SIGTERM, so that wrappers can tell a requested stop from a failure, 141 (as if killed by
+    /// SIGPIPE) when the output was closed, 124 (as with timeout(1)) at the --deadline, and 1
+    /// otherwise.
+    fn exit_code(&self) -> i32 {
+        match self {
+            XtempError::Terminated(_) => 143,
+            XtempError::OutputClosed => 141,
+            XtempError::DeadlineReached(_) => 124,
+            _ => 1,
+        }
+    }
 }
 
 impl fmt::Display for XtempError {
@@ -58,150 +734,1968 @@ impl fmt::Display for XtempError {
             FailedToWrite(e) => write!(f, "could not write to output stream: {}", e),
             SubprocessFailed(msg) => write!(f, "subprocess failed: {}", msg),
             MissingCommand => write!(f, "missing command argument"),
+            ArgsTooLong(requested, max) => write!(
+                f,
+                "batch size {} exceeds the system argument length limit (maximum is {})",
+                requested, max
+            ),
+            InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            FailedToOpen(path, e) => write!(f, "could not open {}: {}", path, e),
+            Terminated(batches) => write!(f, "terminated after {} batches", batches),
+            DeadlineReached(batches) => {
+                write!(f, "stopped at the deadline after {} batches", batches)
+            }
+            RecordTooLong(max) => write!(f, "input line exceeds --max-record-bytes ({})", max),
+            NoSpace(dir, needed, available) => write!(
+                f,
+                "not enough space in {} for the next batch ({} bytes needed, {} available)",
+                dir.display(),
+                needed,
+                available
+            ),
+            Modified(path) => {
+                write!(f, "{} was modified or removed while its batch was running", path.display())
+            }
+            Locked(path) => write!(f, "{} is locked by another process", path.display()),
+            OutputClosed => write!(f, "output closed, so stopping"),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, XtempError>;
 
-fn get_max_open_files() -> usize {
-    match getrlimit(Resource::RLIMIT_NOFILE) {
-        Ok((soft, _hard)) => soft as usize,
-        Err(_) => 1024, // fallback
-    }
-}
-
-/// Replaces replstr with replacements, returning the full literal command.
-fn resolve_replstr(
-    command: &[String],
-    replstr: Option<&str>,
-    replacements: Vec<String>,
-) -> Vec<String> {
-    let mut cmd_args = Vec::new();
-    match replstr {
-        Some(replstr) => {
-            // Replace exact matches of replstr with all replacements
-            for arg in command {
-                if arg == replstr {
-                    cmd_args.extend(replacements.clone());
-                } else {
-                    cmd_args.push(arg.clone());
+/// What stands for the list file in the command with -l, unless -J says otherwise
+const LIST_REPLSTR: &str = "{list}";
+
+/// How long `serve` waits for more lines before dispatching a partial batch, unless
+/// --flush-interval says otherwise
+#[cfg(unix)]
+const DEFAULT_SERVE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to check whether a batch on one of the --ssh hosts has finished, once they're all
+/// busy
+#[cfg(unix)]
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Parses a duration such as "500ms", "30s", "5m" or "1h". A bare number is taken as seconds.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = match unit {
+        "ms" => num / 1000.0,
+        "" | "s" => num,
+        "m" => num * 60.0,
+        "h" => num * 3600.0,
+        _ => return Err(format!("invalid duration unit: {}", unit)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+/// When --deadline is
+#[derive(Clone, Copy, Debug)]
+enum Deadline {
+    At(SystemTime),
+    /// How long after starting
+    After(Duration),
+}
+
+impl Deadline {
+    fn time(self) -> SystemTime {
+        match self {
+            Deadline::At(time) => time,
+            Deadline::After(duration) => SystemTime::now() + duration,
+        }
+    }
+}
+
+/// Parses a --deadline: a timestamp, or otherwise a duration.
+fn parse_deadline(s: &str) -> std::result::Result<Deadline, String> {
+    match log::parse_timestamp(s) {
+        Some(time) => Ok(Deadline::At(time)),
+        None => parse_duration(s).map(Deadline::After).map_err(|_| {
+            format!("invalid deadline: {} (expected a time like 2024-05-06T07:00:00Z or 50m)", s)
+        }),
+    }
+}
+
+/// Parses a byte size such as "512", "64K", "10M" or "1G" (binary multiples).
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    num.checked_mul(multiplier).ok_or_else(|| format!("size too large: {}", s))
+}
+
+/// Parses an octal file mode such as "644" or "0644".
+#[cfg(unix)]
+fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid file mode: {}", s)),
+    }
+}
+
+/// Creates a tempfile, in `dir` if given or else the system temporary directory.
+fn create_tempfile(args: &Args, dir: Option<&TempDir>) -> Result<pool::PoolFile> {
+    #[cfg(target_os = "linux")]
+    if args.memfd {
+        let (file, path) = sys::memfd().map_err(XtempError::FailedToWrite)?;
+        sys::set_file_owner_and_mode(&file, args.file_mode, args.file_group)
+            .map_err(XtempError::FailedToWrite)?;
+        return Ok(pool::PoolFile::memfd(file, path));
+    }
+    let mut builder = tempfile::Builder::new();
+    builder.keep(args.keep_tempfiles());
+    let tmpfile = match dir {
+        Some(dir) => builder.tempfile_in(dir.path()),
+        None => builder.tempfile(),
+    }
+    .map_err(XtempError::FailedToWrite)?;
+    #[cfg(unix)]
+    sys::set_file_owner_and_mode(tmpfile.as_file(), args.file_mode, args.file_group)
+        .map_err(XtempError::FailedToWrite)?;
+    Ok(pool::PoolFile::new(tmpfile, args.cwd_tempdir))
+}
+
+fn create_tempdir(args: &Args) -> Result<TempDir> {
+    let dir = tempfile::Builder::new()
+        .keep(args.keep_tempfiles())
+        .tempdir()
+        .map_err(XtempError::FailedToWrite)?;
+    #[cfg(unix)]
+    sys::set_dir_owner_and_mode(dir.path(), args.file_mode, args.file_group)
+        .map_err(XtempError::FailedToWrite)?;
+    Ok(dir)
+}
+
+/// Parses a KEY=VAL environment variable assignment.
+fn parse_env_var(s: OsString) -> std::result::Result<(OsString, OsString), String> {
+    let bytes = s.as_encoded_bytes();
+    match bytes.iter().position(|&b| b == b'=') {
+        Some(i) if i > 0 => Ok((sys::os_string(&bytes[..i]), sys::os_string(&bytes[i + 1..]))),
+        _ => Err(format!("expected KEY=VAL: {}", s.to_string_lossy())),
+    }
+}
+
+/// Matches a name against a glob pattern in which * matches any run of characters and ? matches
+/// any single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last * seen and the name position it was tried at, for backtracking
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Applies --env-clear, --env-pass and --env to the command.
+fn set_command_env(cmd: &mut Command, args: &Args) {
+    if args.env_clear || !args.env_pass.is_empty() {
+        cmd.env_clear();
+        for (key, val) in std::env::vars_os() {
+            let name = key.to_string_lossy();
+            if args.env_pass.iter().any(|glob| glob_match(glob, &name)) {
+                cmd.env(&key, val);
+            }
+        }
+    }
+    cmd.envs(args.env.iter().map(|(k, v)| (k, v)));
+}
+
+/// Replaces the {#} placeholder with the batch number.
+fn replace_batch_number(s: &str, batch: usize) -> String {
+    s.replace("{#}", &batch.to_string())
+}
+
+/// Returns the number of bytes of argv space left for tempfile arguments once the environment
+/// and `command` itself are accounted for. --env-name's variable is counted by name only, as
+/// its value grows with the batch.
+fn get_arg_budget(args: &Args, command: &[OsString]) -> usize {
+    let command_size: usize = command.iter().map(|a| sys::arg_cost(a)).sum();
+    // Variables set with --env may add to the inherited environment
+    let env_size: usize =
+        args.env.iter().map(|(k, v)| sys::arg_cost(k.as_ref()) + v.len() + 1).sum::<usize>()
+            + args.env_name.as_ref().map_or(0, |name| sys::arg_cost(name.as_ref()));
+    // Leave headroom as recommended by POSIX for xargs implementations
+    sys::get_arg_max()
+        .saturating_sub(sys::get_env_size())
+        .saturating_sub(env_size)
+        .saturating_sub(command_size)
+        .saturating_sub(2048)
+}
+
+/// Returns the maximum number of tempfile arguments, each `len` bytes long and costing `cost`
+/// bytes of `budget` for each of its `slots`, that fit in a single invocation along with the
+/// `joined` words they're part of, or None if tempfile arguments aren't passed at all.
+fn get_max_batch_size(
+    budget: usize,
+    slots: usize,
+    joined: &[input::Joined],
+    cost: usize,
+    len: usize,
+) -> Option<usize> {
+    let per_file = slots * cost + joined.iter().map(|j| len + j.sep).sum::<usize>();
+    if per_file == 0 {
+        return None;
+    }
+    Some(joined.iter().map(|j| j.room / (len + j.sep)).fold(budget / per_file, usize::min))
+}
+
+/// Returns the room in a word that has `fixed` bytes besides the arguments joined into it.
+fn joined_word(fixed: usize, sep: usize) -> input::Joined {
+    // Less the terminator, with one separator fewer than arguments
+    let room = (sys::get_max_arg_strlen() + sep).saturating_sub(fixed + 1);
+    input::Joined { room, sep }
+}
+
+/// Returns the room in each {files:SEP} word for the arguments joined into it, or with
+/// --env-name in its variable, which takes their place.
+fn joined_words(args: &Args, words: &[Word]) -> Vec<input::Joined> {
+    if let Some(ref name) = args.env_name {
+        // NAME=
+        return vec![joined_word(name.len() + 1, args.env_separator.len())];
+    }
+    words
+        .iter()
+        .filter_map(|word| match *word {
+            Word::Joined { before, sep, after, .. } => {
+                Some(joined_word(before.len() + after.len(), sep.len()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Picks the next batch size for --adaptive by scaling the previous one toward the target
+/// duration. Changes are limited to a factor of two per batch to keep one outlier from
+/// throwing off the estimate.
+fn next_adaptive_size(size: usize, elapsed: Duration, target: Duration, max: usize) -> usize {
+    let ratio = target.as_secs_f64() / elapsed.as_secs_f64().max(1e-6);
+    let next = (size as f64 * ratio.clamp(0.5, 2.0)).round() as usize;
+    next.clamp(1, max.max(1))
+}
+
+/// Fails if the filesystem holding the tempfiles doesn't have room for `needed` more bytes, so
+/// that a full disk is reported as such rather than as a half-written batch. Filesystems that
+/// can't report their free space aren't checked.
+fn check_space(dir: Option<&TempDir>, needed: u64) -> Result<()> {
+    let dir = dir.map_or_else(std::env::temp_dir, |dir| dir.path().to_path_buf());
+    match sys::available_space(&dir) {
+        Ok(available) if available < needed => Err(XtempError::NoSpace(dir, needed, available)),
+        _ => Ok(()),
+    }
+}
+
+/// Writes a batch to the start of `pool`, growing it as needed. Returns the indexes of the lines
+/// skipped with --on-write-error skip, whose files are moved after the rest of the batch's.
+fn fill_pool(
+    args: &Args,
+    template: Option<&template::Template>,
+    dir: Option<&TempDir>,
+    pool: &mut Vec<pool::PoolFile>,
+    batch: usize,
+    first_lineno: usize,
+    chunk: &[String],
+) -> Result<Vec<usize>> {
+    while pool.len() < chunk.len() {
+        pool.push(create_tempfile(args, dir)?);
+    }
+    // Space taken by the files being rewritten is freed as they're truncated
+    let needed: u64 = chunk
+        .iter()
+        .map(|line| input::strip_delimiter(line, args.null).len() as u64)
+        .sum::<u64>()
+        + if args.keep_newlines { chunk.len() as u64 } else { 0 }
+        + template.map_or(0, |t| (t.literal_len() * chunk.len()) as u64);
+    let reclaimed: u64 = pool[..chunk.len()].iter().map(pool::PoolFile::size).sum();
+    // Unless told to abort, find out the hard way which of the lines don't fit
+    if args.on_disk() {
+        match check_space(dir, needed.saturating_sub(reclaimed)) {
+            Err(e) if args.on_write_error != pool::OnWriteError::Abort => {
+                log::error(&e.to_string())
+            }
+            result => result?,
+        }
+    }
+    let files = &mut pool[..chunk.len()];
+    let skipped = write_tempfiles(args, template, batch, first_lineno, files, chunk)?;
+    for &i in skipped.iter().rev() {
+        files[i..].rotate_left(1);
+    }
+    Ok(skipped)
+}
+
+/// Removes the lines skipped with --on-write-error skip from a batch, returning them.
+fn remove_skipped<T>(lines: &mut Vec<T>, skipped: &[usize]) -> Vec<T> {
+    let mut removed: Vec<T> = skipped.iter().rev().map(|&i| lines.remove(i)).collect();
+    removed.reverse();
+    removed
+}
+
+/// Writes each line to its own tempfile, spreading the work over --write-threads threads.
+/// `first_lineno` is the line number of the first line, for templates. Returns the indexes of
+/// the lines skipped with --on-write-error skip.
+fn write_tempfiles(
+    args: &Args,
+    template: Option<&template::Template>,
+    batch: usize,
+    first_lineno: usize,
+    files: &mut [pool::PoolFile],
+    lines: &[String],
+) -> Result<Vec<usize>> {
+    // `offset` is the index of the first of `files` in the batch
+    let write = |files: &mut [pool::PoolFile], lines: &[String], offset: usize| {
+        let mut rendered = String::new();
+        let mut skipped = Vec::new();
+        for (i, (tmpfile, line)) in files.iter_mut().zip(lines).enumerate() {
+            let lineno = first_lineno + offset + i;
+            let line = input::strip_delimiter(line, args.null);
+            let content = match template {
+                Some(template) => {
+                    rendered.clear();
+                    let context = template::Context { record: line, lineno, batch };
+                    template.render(&context, &mut rendered);
+                    rendered.as_str()
                 }
+                None => line,
+            };
+            let written = tmpfile.overwrite_with_policy(args.on_write_error, args.fsync, |file| {
+                pool::write_record(file, content.as_bytes(), args.keep_newlines)
+            })?;
+            if !written {
+                skipped.push(offset + i);
+                continue;
+            }
+            log::debug(&format!("wrote {} bytes to {}", content.len(), tmpfile.path().display()));
+        }
+        Ok(skipped)
+    };
+    let threads = (args.write_threads as usize).min(files.len());
+    if threads <= 1 {
+        return write(files, lines, 0);
+    }
+    let per_thread = files.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks_mut(per_thread)
+            .zip(lines.chunks(per_thread))
+            .enumerate()
+            .map(|(i, (files, lines))| {
+                scope.spawn(move || write(files, lines, i * per_thread))
+            })
+            .collect();
+        let mut skipped = Vec::new();
+        for handle in handles {
+            skipped.extend(handle.join().expect("tempfile writer panicked")?);
+        }
+        Ok(skipped)
+    })
+}
+
+/// Runs a further shell command on a batch, for --fallback or --and, after it last exited with
+/// `code`. Its output is added to the command's, and its exit code is returned.
+fn run_batch_command(
+    args: &Args,
+    name: &str,
+    script: &str,
+    files: &[pool::PoolFile],
+    batch: usize,
+    list: Option<&Path>,
+    code: i32,
+) -> Result<i32> {
+    log::info(&format!("running {}", name));
+    let mut cmd = sys::shell_command(script);
+    cmd.args(files.iter().map(pool::PoolFile::path)).stdin(Stdio::null());
+    set_command_env(&mut cmd, args);
+    set_batch_env(&mut cmd, args, batch, list);
+    cmd.env("XTEMP_EXIT_STATUS", code.to_string());
+    let (stdout, stderr) = open_output_files(args, batch, true)?;
+    let status = cmd
+        .stdout(output_stdio(stdout))
+        .stderr(output_stdio(stderr))
+        .status()
+        .map_err(|e| XtempError::SubprocessFailed(format!("{}: {}", name, e)))?;
+    let code = sys::exit_code(status);
+    log::info(&format!("{} exited with code {}", name, code));
+    Ok(code)
+}
+
+/// A batch's command output, captured for --then, and where the --then stage's output goes
+struct Captured {
+    output: Vec<u8>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+/// Runs the --then stage on a batch's captured output, writing each line of it to a tempfile from
+/// `pool`, and returns its exit code. The lines are taken as bytes and batched like the input,
+/// by -n, -s and ARG_MAX, running CMD once for each batch until one fails. There's nothing to
+/// do if the command printed nothing.
+fn run_then(
+    args: &Args,
+    then: &str,
+    captured: Captured,
+    pool: &mut Vec<pool::PoolFile>,
+    batch_dir: Option<&TempDir>,
+    batch: usize,
+) -> Result<i32> {
+    let delimiter = if args.null { b'\0' } else { b'\n' };
+    let mut lines = captured.output.split_inclusive(|&b| b == delimiter).peekable();
+    if lines.peek().is_none() {
+        log::info("command printed nothing, so not running --then");
+        return Ok(0);
+    }
+    if pool.is_empty() {
+        pool.push(create_tempfile(args, batch_dir)?);
+    }
+    let path = pool[0].path().as_os_str();
+    let budget = get_arg_budget(args, &[then.into()]);
+    let max = get_max_batch_size(budget, 1, &[], sys::arg_cost(path), path.len()).unwrap_or(1);
+    let batch_size =
+        args.batch_size.unwrap_or_else(|| sys::get_max_open_files().saturating_sub(32)).min(max);
+
+    let mut code = 0;
+    while lines.peek().is_some() && code == 0 {
+        // Fill the batch's tempfiles, up to -n of them and -s bytes, with at least one line
+        let mut count = 0;
+        let mut bytes = 0;
+        while let Some(&line) = lines.peek() {
+            let content = line.strip_suffix(&[delimiter]).unwrap_or(line);
+            bytes += content.len() as u64 + args.keep_newlines as u64;
+            let full = count == batch_size || args.max_bytes.is_some_and(|max| bytes > max);
+            if full && count > 0 {
+                break;
+            }
+            if pool.len() == count {
+                pool.push(create_tempfile(args, batch_dir)?);
             }
+            pool[count].overwrite(args.fsync, |file| {
+                pool::write_record(file, content, args.keep_newlines)
+            })?;
+            lines.next();
+            count += 1;
         }
-        None => {
-            // Append all replacements as trailing arguments
-            cmd_args.extend(command.iter().cloned());
-            cmd_args.extend(replacements);
+
+        log::info(&format!("running --then with {} tempfiles", count));
+        let output_file = |file: &Option<File>| {
+            file.as_ref().map(File::try_clone).transpose().map_err(XtempError::FailedToWrite)
+        };
+        let mut cmd = sys::shell_command(then);
+        cmd.args(pool[..count].iter().map(pool::PoolFile::path)).stdin(Stdio::null());
+        set_command_env(&mut cmd, args);
+        set_batch_env(&mut cmd, args, batch, None);
+        let status = cmd
+            .stdout(output_stdio(output_file(&captured.stdout)?))
+            .stderr(output_stdio(output_file(&captured.stderr)?))
+            .status()
+            .map_err(|e| XtempError::SubprocessFailed(format!("--then: {}", e)))?;
+        code = sys::exit_code(status);
+    }
+    // Otherwise the next batch's command would find them in --cwd-tempdir's directory
+    if batch_dir.is_some() {
+        pool.clear();
+    }
+    Ok(code)
+}
+
+/// Returns checksums of a batch's files, for --verify.
+fn checksum_files<'a>(files: impl Iterator<Item = &'a pool::PoolFile>) -> Result<Vec<u64>> {
+    files
+        .map(|f| {
+            f.checksum().map_err(|e| XtempError::FailedToOpen(f.path().display().to_string(), e))
+        })
+        .collect()
+}
+
+/// Fails if any of a batch's files no longer match the checksums taken before it ran.
+fn verify_files<'a>(
+    files: impl Iterator<Item = &'a pool::PoolFile>,
+    checksums: &[u64],
+) -> Result<()> {
+    for (f, &checksum) in files.zip(checksums) {
+        match f.checksum() {
+            Ok(c) if c == checksum => {}
+            Ok(_) => return Err(XtempError::Modified(f.path().to_path_buf())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(XtempError::Modified(f.path().to_path_buf()))
+            }
+            Err(e) => return Err(XtempError::FailedToOpen(f.path().display().to_string(), e)),
         }
     }
-    cmd_args
+    log::debug(&format!("verified {} files", checksums.len()));
+    Ok(())
+}
+
+/// Writes the paths of a batch's tempfiles to stdout for --no-run, followed by an empty line.
+fn print_paths(tempfiles: &[pool::PoolFile]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    tempfiles
+        .iter()
+        .try_for_each(|f| writeln!(stdout, "{}", f.path().display()))
+        .and_then(|_| writeln!(stdout))
+        .and_then(|_| stdout.flush())
+        .map_err(XtempError::FailedToWrite)
+}
+
+/// Writes a batch's lines to stdout for --tee. With `ignore_sigpipe`, stdout having been closed
+/// isn't an error.
+fn tee_lines(lines: &[String], ignore_sigpipe: bool) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    let result = lines
+        .iter()
+        .try_for_each(|line| stdout.write_all(line.as_bytes()))
+        .and_then(|_| stdout.flush());
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe && ignore_sigpipe => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Err(XtempError::OutputClosed),
+        result => result.map_err(XtempError::FailedToWrite),
+    }
+}
+
+/// Sets the environment variables that describe the current batch to the command and hooks.
+fn set_batch_env(cmd: &mut Command, args: &Args, batch: usize, list: Option<&Path>) {
+    cmd.env("XTEMP_BATCH", batch.to_string());
+    if let Some(path) = list {
+        cmd.env("XTEMP_LIST", path);
+    }
+    if let Some(dir) = job_dir(args, batch) {
+        cmd.env("XTEMP_JOB_DIR", dir);
+    }
+}
+
+/// Returns the --results directory for a batch.
+fn job_dir(args: &Args, batch: usize) -> Option<PathBuf> {
+    args.results.as_ref().map(|dir| dir.join(batch.to_string()))
+}
+
+/// Returns the command to run with its environment, working directory and process settings
+/// applied. `batch` is None for a --persistent command, which outlives any one batch.
+fn build_command<'a>(
+    args: &Args,
+    argv: impl IntoIterator<Item = &'a OsStr>,
+    batch: Option<usize>,
+    list: Option<&Path>,
+    batch_dir: Option<&TempDir>,
+) -> Command {
+    let mut argv = argv.into_iter();
+    let mut command = Command::new(argv.next().expect("command is not empty"));
+    command.args(argv);
+    set_command_env(&mut command, args);
+    if let Some(batch) = batch {
+        set_batch_env(&mut command, args, batch, list);
+    } else if let Some(path) = list {
+        command.env("XTEMP_LIST", path);
+    }
+    if let Some(dir) = batch_dir {
+        command.current_dir(dir.path());
+    } else if let Some(ref cwd) = args.cwd {
+        command.current_dir(replace_batch_number(cwd, batch.unwrap_or(1)));
+    }
+    #[cfg(unix)]
+    sys::ChildSetup {
+        nice: args.nice,
+        #[cfg(target_os = "linux")]
+        ioprio: args.ionice,
+        limit_mem: args.limit_mem,
+        limit_cpu: args.limit_cpu,
+        limit_fsize: args.limit_fsize,
+        setsid: args.setsid,
+    }
+    .apply(&mut command);
+    command
+}
+
+/// Starts the command, trying again up to --spawn-retries times if that fails for want of
+/// processes or memory, which on a busy host rarely lasts. Returns the child and how many times
+/// it took trying again.
+fn spawn_with_backoff(args: &Args, command: &mut Command) -> Result<(Child, u32)> {
+    let mut delay = args.spawn_retry_delay;
+    let mut retries = 0;
+    loop {
+        let e = match command.spawn() {
+            Ok(child) => return Ok((child, retries)),
+            Err(e) => e,
+        };
+        let transient = matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::OutOfMemory);
+        if !transient || retries == args.spawn_retries {
+            return Err(XtempError::SubprocessFailed(e.to_string()));
+        }
+        log::error(&format!("failed to start command ({}), trying again in {:?}", e, delay));
+        std::thread::sleep(delay);
+        delay *= 2;
+        retries += 1;
+    }
+}
+
+/// Runs a --pre-hook or --post-hook, failing if it exits non-zero.
+fn run_hook(
+    name: &str,
+    hook: &str,
+    files: &[pool::PoolFile],
+    env: impl FnOnce(&mut Command),
+) -> Result<()> {
+    log::info(&format!("running {}", name));
+    let mut cmd = sys::shell_command(hook);
+    cmd.args(files.iter().map(pool::PoolFile::path)).stdin(Stdio::null());
+    env(&mut cmd);
+    let status = cmd
+        .status()
+        .map_err(|e| XtempError::SubprocessFailed(format!("{}: {}", name, e)))?;
+    if !status.success() {
+        return Err(XtempError::SubprocessFailed(format!(
+            "{} exited with code {}",
+            name,
+            sys::exit_code(status)
+        )));
+    }
+    Ok(())
+}
+
+/// Opens the --stdout-file and --stderr-file for a batch, returning where the command's stdout
+/// and stderr should go. With `append` they're added to rather than replaced.
+fn open_output_files(
+    args: &Args,
+    batch: usize,
+    append: bool,
+) -> Result<(Option<File>, Option<File>)> {
+    let create = |path: &PathBuf| {
+        File::options()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|e| XtempError::FailedToOpen(path.display().to_string(), e))
+    };
+    let path = |pattern: &Option<String>, name: &str| match pattern {
+        Some(pattern) => Some(PathBuf::from(replace_batch_number(pattern, batch))),
+        None => job_dir(args, batch).map(|dir| dir.join(name)),
+    };
+    let stdout_path = path(&args.stdout_file, "stdout");
+    let stderr_path = path(&args.stderr_file, "stderr");
+    let stdout = stdout_path.as_ref().map(create).transpose()?;
+    let stderr = match stdout {
+        // Both streams going to the same file have to share it, or they'd overwrite each other
+        Some(ref file) if stdout_path == stderr_path => {
+            Some(file.try_clone().map_err(XtempError::FailedToWrite)?)
+        }
+        _ => stderr_path.as_ref().map(create).transpose()?,
+    };
+    Ok((stdout, stderr))
+}
+
+/// Sends a command's output to `file`, or if there isn't one to wherever ours goes.
+fn output_stdio(file: Option<File>) -> Stdio {
+    file.map_or_else(Stdio::inherit, Stdio::from)
+}
+
+/// Writes the batch's lines to the command's stdin for --stdin-content. The command is free to
+/// exit without reading all of it.
+fn write_stdin_content(stdin: ChildStdin, lines: &[String], null: bool) -> Result<()> {
+    let mut stdin = io::BufWriter::new(stdin);
+    let terminator = if null { '\0' } else { '\n' };
+    let result = lines
+        .iter()
+        .try_for_each(|line| write!(stdin, "{}{}", input::strip_delimiter(line, null), terminator))
+        .and_then(|_| stdin.flush());
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(XtempError::FailedToWrite(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Copies a batch's tempfiles to an --ssh host, returning the command that runs the batch there.
+#[cfg(unix)]
+fn remote_command(
+    args: &Args,
+    words: &[Word],
+    host: &remote::Host,
+    batch: usize,
+    files: &[pool::PoolFile],
+    records: &[&str],
+) -> Result<Command> {
+    let paths = host.upload(files.iter().map(pool::PoolFile::path))?;
+    let joined = join_files(words, &paths, |p| p.as_ref());
+    // The command runs in a shell there, so it's quoted as text
+    let argv: Vec<_> = expand_words(words, &paths, |p| p.as_ref(), records, &joined)
+        .map(OsStr::to_string_lossy)
+        .collect();
+    let argv: Vec<&str> = argv.iter().map(AsRef::as_ref).collect();
+    status::command_started(argv[0], paths.len());
+    log::info(&format!("running {} on {} with {} tempfiles", argv[0], host.dest(), paths.len()));
+    let mut env = vec![("XTEMP_BATCH", batch.to_string().into())];
+    let vars: Vec<_> = args.env.iter().map(|(k, v)| (k.to_string_lossy(), v)).collect();
+    env.extend(vars.iter().map(|(k, v)| (k.as_ref(), v.to_string_lossy())));
+    Ok(host.command(&argv, &env))
+}
+
+/// A word of the command: either given literally, or a placeholder for the tempfile arguments or
+/// the lines themselves.
+#[derive(Clone, Copy, Debug)]
+enum Word<'a> {
+    Literal(&'a OsStr),
+    Tempfiles,
+    Records,
+    /// An argument containing {files:SEP}, which is replaced by the tempfile arguments joined
+    /// with SEP. `index` counts these words from 0
+    Joined { index: usize, before: &'a OsStr, sep: &'a OsStr, after: &'a OsStr },
+}
+
+/// Finds {files:SEP} in an argument, returning the text before it, SEP and the text after it.
+fn files_placeholder(arg: &OsStr) -> Option<(&OsStr, &OsStr, &OsStr)> {
+    let arg = arg.as_encoded_bytes();
+    let start = arg.windows(b"{files:".len()).position(|w| w == b"{files:")?;
+    let rest = &arg[start + b"{files:".len()..];
+    let end = rest.iter().position(|&b| b == b'}')?;
+    Some((sys::os_str(&arg[..start]), sys::os_str(&rest[..end]), sys::os_str(&rest[end + 1..])))
+}
+
+/// Splits the command into words once, so that each batch's command line can be put together
+/// without copying it. Exact matches of replstr stand for the tempfiles, which otherwise follow
+/// the command, and exact matches of `record_replstr` for the lines. Either of them preceded by
+/// `escape` stands for itself, as do an argument containing {files:SEP} and `list`, the {list}
+/// that -l may use, whether or not the command uses it. They're all matched byte for byte, so
+/// any of them may be or sit next to text that isn't valid UTF-8.
+fn command_words<'a>(
+    command: &'a [OsString],
+    replstr: Option<&OsStr>,
+    record_replstr: Option<&OsStr>,
+    escape: Option<&OsStr>,
+    list: Option<&OsStr>,
+) -> Vec<Word<'a>> {
+    let mut joined = 0;
+    let mut words: Vec<Word> = command
+        .iter()
+        .map(|arg| {
+            let arg = arg.as_os_str();
+            let escaped = escape.and_then(|escape| {
+                arg.as_encoded_bytes().strip_prefix(escape.as_encoded_bytes()).map(sys::os_str)
+            });
+            if let Some(escaped) = escaped {
+                if Some(escaped) == replstr
+                    || Some(escaped) == record_replstr
+                    || Some(escaped) == list
+                    || files_placeholder(escaped).is_some()
+                {
+                    return Word::Literal(escaped);
+                }
+            }
+            if let Some((before, sep, after)) = files_placeholder(arg) {
+                joined += 1;
+                return Word::Joined { index: joined - 1, before, sep, after };
+            }
+            match (replstr, record_replstr) {
+                (Some(replstr), _) if arg == replstr => Word::Tempfiles,
+                (_, Some(record_replstr)) if arg == record_replstr => Word::Records,
+                _ => Word::Literal(arg),
+            }
+        })
+        .collect();
+    if replstr.is_none() && joined == 0 {
+        words.push(Word::Tempfiles);
+    }
+    words
+}
+
+/// Returns what each {files:SEP} word of a batch's command line expands to, with `arg` giving
+/// the argument for each of `tempfiles`.
+fn join_files<'a, T>(
+    words: &[Word],
+    tempfiles: &'a [T],
+    arg: impl Fn(&'a T) -> &'a OsStr,
+) -> Vec<OsString> {
+    words
+        .iter()
+        .filter_map(|word| match *word {
+            Word::Joined { before, sep, after, .. } => {
+                let files: Vec<&OsStr> = tempfiles.iter().map(&arg).collect();
+                let mut joined = OsString::from(before);
+                joined.push(files.join(OsStr::new(sep)));
+                joined.push(after);
+                Some(joined)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns a batch's command line, with `arg` giving the argument for each of `tempfiles` and
+/// `joined` the {files:SEP} words as expanded by join_files.
+fn expand_words<'a, T>(
+    words: &'a [Word<'a>],
+    tempfiles: &'a [T],
+    arg: impl Fn(&'a T) -> &'a OsStr + Copy + 'a,
+    records: &'a [&'a str],
+    joined: &'a [OsString],
+) -> impl Iterator<Item = &'a OsStr> + 'a {
+    words.iter().flat_map(move |word| {
+        let (literal, tempfiles, records) = match *word {
+            Word::Literal(literal) => (Some(literal), &tempfiles[..0], &records[..0]),
+            Word::Joined { index, .. } => {
+                (Some(joined[index].as_os_str()), &tempfiles[..0], &records[..0])
+            }
+            Word::Tempfiles => (None, tempfiles, &records[..0]),
+            Word::Records => (None, &tempfiles[..0], records),
+        };
+        let records = records.iter().map(OsStr::new);
+        literal.into_iter().chain(tempfiles.iter().map(arg)).chain(records)
+    })
 }
 
 fn main() {
-    let args = Args::parse();
-    let result = run(args);
+    let result = config::args_with_defaults(&Args::command())
+        .and_then(|argv| run(Args::parse_from(&argv), &argv));
 
     match result {
         Ok(_) => {}
         Err(e) => {
-            eprintln!("xtemp: {}", e);
-            process::exit(1);
+            // Stopping because the output was closed is how pipelines normally end
+            match e {
+                XtempError::OutputClosed => log::info(&e.to_string()),
+                _ => log::error(&e.to_string()),
+            }
+            process::exit(e.exit_code());
         }
     }
 
 }
 
-fn run(args: Args) -> Result<()> {
-    if args.command.is_empty() {
+/// Runs xtemp as invoked with `argv`, after defaults from the config have been added.
+fn run(mut args: Args, argv: &[OsString]) -> Result<()> {
+    log::init(args.log_format, args.verbose, args.quiet);
+    // Before any input is read, since records may be left out as soon as they are
+    rejects::open(args.rejects.as_deref(), args.record_format().encoding)?;
+    if let Some(deadline) = args.deadline {
+        #[cfg(unix)]
+        let kill = args.deadline_kill;
+        #[cfg(not(unix))]
+        let kill = false;
+        status::set_deadline(deadline.time(), kill);
+    }
+    let input = match args.subcommand.take() {
+        Some(Subcommands::Completions { shell }) => {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut Args::command(), "xtemp", &mut script);
+            return io::stdout().write_all(&script).map_err(XtempError::FailedToWrite);
+        }
+        Some(Subcommands::Man) => {
+            let mut page = Vec::new();
+            man::render(Args::command(), &mut page).map_err(XtempError::FailedToWrite)?;
+            return io::stdout().write_all(&page).map_err(XtempError::FailedToWrite);
+        }
+        #[cfg(unix)]
+        Some(Subcommands::Serve { socket, command }) => {
+            args.command = command;
+            // Clients can't be expected to close off a batch, so don't let one sit indefinitely
+            let flush_interval = args.flush_interval.or(Some(DEFAULT_SERVE_FLUSH_INTERVAL));
+            let input = input::Input::socket(&socket, args.record_format(), flush_interval)
+                .map_err(|e| XtempError::FailedToOpen(socket.display().to_string(), e))?;
+            log::info(&format!("listening on {}", socket.display()));
+            input
+        }
+        Some(Subcommands::Replay { results, only_failed }) => {
+            status::start();
+            return replay(&results, only_failed);
+        }
+        Some(Subcommands::Bench { records, record_size, batch_sizes, dir, command }) => {
+            let dirs = dir;
+            return bench::run(&bench::Options { records, record_size, batch_sizes, dirs, command });
+        }
+        None => match args.input_cmd {
+            Some(ref command) => {
+                input::Input::command(command, args.record_format(), args.flush_interval)?
+            }
+            None if args.arg.is_empty() && args.arg_file.is_empty() => {
+                input::Input::stdin(args.record_format(), args.follow, args.flush_interval)
+            }
+            None => input::Input::sources(
+                input_sources(&args)?,
+                args.merge,
+                args.record_format(),
+                args.follow,
+                args.flush_interval,
+            ),
+        },
+    };
+    status::start();
+    run_input(args, input, Some(argv))
+}
+
+/// Runs the batches replay reads from each batch's recorded input, using the options in
+/// DIR/args. Batches are numbered as they were originally, and every selected batch is run
+/// even if an earlier one fails.
+fn replay(dir: &Path, only_failed: bool) -> Result<()> {
+    let open_error = |path: &Path, e| XtempError::FailedToOpen(path.display().to_string(), e);
+    let args_path = dir.join("args");
+    let recorded = std::fs::read(&args_path).map_err(|e| open_error(&args_path, e))?;
+    // Each argument is terminated by NUL
+    let recorded_args = recorded.iter().filter(|&&b| b == 0).count();
+    let mut batches: Vec<usize> = std::fs::read_dir(dir)
+        .map_err(|e| open_error(dir, e))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    batches.sort_unstable();
+
+    let (mut replayed, mut failed) = (0, 0);
+    for batch in batches {
+        let job_dir = dir.join(batch.to_string());
+        if only_failed && job::read_number(&job_dir, "XTEMP_EXIT_STATUS") == Some(0) {
+            continue;
+        }
+        let input_path = job_dir.join("input");
+        let file = File::open(&input_path).map_err(|e| open_error(&input_path, e))?;
+        let first_line = job::read_number(&job_dir, "XTEMP_FIRST_LINE").unwrap_or(1);
+
+        let argv = std::iter::once(OsString::from("xtemp"))
+            .chain(recorded.split(|&b| b == 0).map(sys::os_string).take(recorded_args));
+        let mut args = Args::parse_from(argv);
+        #[cfg(unix)]
+        if let Some(Subcommands::Serve { command, .. }) = args.subcommand.take() {
+            args.command = command;
+        }
+        // The recorded input has already been through these, and mustn't be split up again
+        args.results = None;
+        args.from_encoding = None;
+        args.map = None;
+        args.skip_processed = false;
+        args.adaptive = false;
+        #[cfg(unix)]
+        {
+            args.pick = false;
+        }
+        args.replay_from = Some((batch, first_line.max(1) as usize));
+
+        log::info(&format!("replaying batch {}", batch));
+        replayed += 1;
+        rejects::open(args.rejects.as_deref(), None)?;
+        let input = input::Input::sources(
+            vec![input::Source::File(file)],
+            input::Merge::Concat,
+            args.record_format(),
+            false,
+            None,
+        );
+        if let Err(e) = run_input(args, input, None) {
+            log::error(&format!("batch {}: {}", batch, e));
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(XtempError::SubprocessFailed(format!(
+            "{} of {} replayed batches failed",
+            failed, replayed
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the command on batches of `input`, recording `argv` for replay if it's given.
+fn run_input(args: Args, input: input::Input, argv: Option<&[OsString]>) -> Result<()> {
+    if args.command.is_empty() && !args.no_run {
         return Err(XtempError::MissingCommand);
     }
+    let input = match args.map {
+        Some(ref map) => input.map(map)?,
+        None => input,
+    };
+    let input = match args.state_dir {
+        Some(ref dir) if args.skip_processed => {
+            let processed = state::load(dir)
+                .map_err(|e| XtempError::FailedToOpen(dir.display().to_string(), e))?;
+            log::info(&format!("{} records already processed will be skipped", processed.len()));
+            input.filter(move |record| !processed.contains(&state::hash(record)))
+        }
+        _ => input,
+    };
+    #[cfg(unix)]
+    let input = if args.pick { input.pick()? } else { input };
+
+    #[cfg(unix)]
+    let progress_out = match args.progress_fd {
+        Some(fd) => Some(
+            sys::open_fd(fd).map_err(|e| XtempError::FailedToOpen(format!("fd {}", fd), e))?,
+        ),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let progress_out = None;
+    let mut progress = progress::Progress::new(progress_out);
+    // Held until the run is over
+    let _lock = match args.lock {
+        Some(ref path) => Some(take_lock(path, !args.lock_nowait)?),
+        None => None,
+    };
+    // Replay reads the options back from here. It's written under the lock so that a run
+    // waiting for it doesn't overwrite those of the one holding it
+    if let (Some(dir), Some(argv)) = (args.results.as_ref(), argv) {
+        let path = dir.join("args");
+        let recorded: Vec<u8> =
+            argv[1..].iter().flat_map(|arg| [arg.as_encoded_bytes(), b"\0"].concat()).collect();
+        std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&path, recorded))
+            .map_err(|e| XtempError::FailedToOpen(path.display().to_string(), e))?;
+    }
+    #[cfg(unix)]
+    if args.setsid {
+        status::forward_interrupts();
+    }
+    let result = run_batches(args, input, &mut progress);
+    progress.finish(&result);
+    result
+}
+
+/// Locks the --lock file, waiting for it if `wait` is set and it's already locked.
+fn take_lock(path: &Path, wait: bool) -> Result<File> {
+    let open_error = |e| XtempError::FailedToOpen(path.display().to_string(), e);
+    let file = File::options().create(true).append(true).open(path).map_err(open_error)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) if wait => {
+            log::info(&format!("waiting for the lock on {}", path.display()));
+            file.lock().map_err(open_error)?;
+        }
+        Err(std::fs::TryLockError::WouldBlock) => return Err(XtempError::Locked(path.into())),
+        Err(std::fs::TryLockError::Error(e)) => return Err(open_error(e)),
+    }
+    Ok(file)
+}
+
+/// Returns where input comes from given --arg and --arg-file: the --arg lines, followed by the
+/// --arg-file files, or stdin if there are none. Files are opened up front so that a missing one
+/// is reported before anything runs.
+fn input_sources(args: &Args) -> Result<Vec<input::Source>> {
+    let delimiter = if args.null { '\0' } else { '\n' };
+    let mut sources = Vec::new();
+    if !args.arg.is_empty() {
+        let records = args.arg.iter().map(|arg| format!("{}{}", arg, delimiter)).collect();
+        sources.push(input::Source::Records(records));
+    }
+    for path in &args.arg_file {
+        if path.as_os_str() == "-" {
+            sources.push(input::Source::Stdin);
+            continue;
+        }
+        let file = File::open(path)
+            .map_err(|e| XtempError::FailedToOpen(path.display().to_string(), e))?;
+        sources.push(input::Source::File(file));
+    }
+    if args.arg_file.is_empty() {
+        sources.push(input::Source::Stdin);
+    }
+    Ok(sources)
+}
+
+/// A batch whose command has exited, with what's needed to finish it off
+struct Finished<'a> {
+    batch: usize,
+    chunk: &'a [String],
+    /// How many lines the chunk holds, which with --group-by is more than its length
+    lines: usize,
+    files: &'a [pool::PoolFile],
+    lists: &'a [pool::PoolFile],
+    code: i32,
+    started: Instant,
+    usage: Option<sys::Usage>,
+    spawn_retries: u32,
+    captured: Option<Captured>,
+    job: Option<job::Job>,
+    job_dir: Option<PathBuf>,
+    checksums: Vec<u64>,
+    /// With --fresh-files, the previous batch's files, removed once this batch is done
+    retired: Vec<pool::PoolFile>,
+    retired_dir: Option<TempDir>,
+}
+
+/// Finishes off a batch once its command has exited: runs --then, the fallback, --and and the
+/// hooks, records how it went, and fails if it did.
+fn finish_batch(
+    args: &Args,
+    done: Finished,
+    then_pool: &mut Vec<pool::PoolFile>,
+    batch_dir: Option<&TempDir>,
+    progress: &mut progress::Progress,
+    state: &mut Option<state::State>,
+) -> Result<()> {
+    let Finished { batch, chunk, files, lists, code, started, usage, .. } = done;
+    let mut job = done.job;
+    let tempfiles = if args.list { lists } else { files };
+    let list_path = lists.first().map(|l| l.path());
+    log::info(&format!(
+        "command exited with code {} after {:.3}s",
+        code,
+        started.elapsed().as_secs_f64()
+    ));
+    if let Some(ref usage) = usage {
+        log::info(&format!(
+            "command used {} KiB at most, {:.3}s user and {:.3}s system CPU time",
+            usage.max_rss,
+            usage.user.as_secs_f64(),
+            usage.system.as_secs_f64()
+        ));
+    }
+    let code = match (&args.then, done.captured) {
+        (Some(then), Some(captured)) if code == 0 => {
+            let code = run_then(args, then, captured, then_pool, batch_dir, batch)?;
+            log::info(&format!("--then exited with code {}", code));
+            code
+        }
+        _ => code,
+    };
+    let mut code = match args.fallback {
+        Some(ref fallback) if code != 0 => {
+            run_batch_command(args, "fallback", fallback, files, batch, list_path, code)?
+        }
+        _ => code,
+    };
+    for and in &args.and {
+        if code != 0 && !args.and_keep_going {
+            break;
+        }
+        let and_code = run_batch_command(args, "--and", and, tempfiles, batch, list_path, code)?;
+        // The batch fails with the first failure's exit code
+        if code == 0 {
+            code = and_code;
+        }
+    }
+
+    if let (Some(job), Some(dir)) = (job.as_mut(), done.job_dir.as_ref()) {
+        job.finished = Some((code, started.elapsed()));
+        job.spawn_retries = done.spawn_retries;
+        job.usage = usage;
+        job.write(dir).map_err(XtempError::FailedToWrite)?;
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    progress.batch_finished(batch, code, elapsed, usage.as_ref())?;
+    status::batch_finished(done.lines, code);
 
-    let batch_size = args.batch_size.unwrap_or_else(|| {
+    let set_env = |cmd: &mut Command| {
+        set_batch_env(cmd, args, batch, list_path);
+        cmd.env("XTEMP_EXIT_STATUS", code.to_string());
+    };
+    if let Some(ref hook) = args.on_fail {
+        if code != 0 {
+            run_hook("on-fail", hook, files, set_env)?;
+        }
+    }
+    if let Some(ref hook) = args.post_hook {
+        run_hook("post-hook", hook, files, set_env)?;
+    }
+    if args.verify {
+        verify_files(files.iter().chain(lists), &done.checksums)?;
+    }
+
+    if code != 0 {
+        // The command most likely failed because its output was closed
+        if !args.ignore_sigpipe && sys::output_closed() {
+            return Err(XtempError::OutputClosed);
+        }
+        rejects::add(chunk, args.null)?;
+        // The command was most likely stopped at the deadline
+        if status::past_deadline() {
+            return Err(XtempError::DeadlineReached(batch));
+        }
+        return Err(XtempError::SubprocessFailed(format!("command exited with code {}", code)));
+    }
+    if let Some(ref mut state) = state {
+        state.add(chunk, args.null).map_err(XtempError::FailedToWrite)?;
+    }
+
+    if !done.retired.is_empty() && !args.keep_tempfiles() {
+        log::info(&format!("removing {} tempfiles from batch {}", done.retired.len(), batch - 1));
+    }
+    drop(done.retired);
+    drop(done.retired_dir);
+    Ok(())
+}
+
+/// A batch running on one of the --ssh hosts while the others get theirs
+#[cfg(unix)]
+struct Running {
+    host: usize,
+    /// The command, until it's been waited for
+    child: Option<Child>,
+    /// With --then, what reads the command's output, and where the --then stage's output goes
+    reader: Option<std::thread::JoinHandle<io::Result<Vec<u8>>>>,
+    then_outputs: Option<(Option<File>, Option<File>)>,
+    batch: usize,
+    chunk: Vec<String>,
+    lines: usize,
+    pool: Vec<pool::PoolFile>,
+    started: Instant,
+    spawn_retries: u32,
+    job: Option<job::Job>,
+    job_dir: Option<PathBuf>,
+    checksums: Vec<u64>,
+    retired: Vec<pool::PoolFile>,
+    null: bool,
+}
+
+#[cfg(unix)]
+impl Drop for Running {
+    /// Waits for a command still running when xtemp stops early, so that its host's directory
+    /// isn't removed from under it. Its lines are rejected if it fails.
+    fn drop(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        log::info(&format!("waiting for batch {} to finish before stopping", self.batch));
+        if !sys::wait_with_usage(&mut child).is_ok_and(|(status, _)| status.success()) {
+            if let Err(e) = rejects::add(&self.chunk, self.null) {
+                log::error(&format!("--rejects: {}", e));
+            }
+        }
+        status::child_finished(child.id());
+    }
+}
+
+/// Waits for whichever of the `running` batches finishes first and takes it out, returning it
+/// with its command's exit status and resource usage.
+#[cfg(unix)]
+fn wait_for_any(
+    running: &mut Vec<Running>,
+) -> Result<(Running, process::ExitStatus, Option<sys::Usage>)> {
+    loop {
+        for i in 0..running.len() {
+            let child = running[i].child.as_mut().expect("running batches have a command");
+            let waited = sys::try_wait_with_usage(child).map_err(|_| {
+                XtempError::SubprocessFailed("failed to wait for command".into())
+            })?;
+            if let Some((status, usage)) = waited {
+                let mut done = running.remove(i);
+                if let Some(child) = done.child.take() {
+                    status::child_finished(child.id());
+                }
+                return Ok((done, status, usage));
+            }
+        }
+        std::thread::sleep(REMOTE_POLL_INTERVAL);
+    }
+}
+
+/// Runs the command on each batch of input until it's exhausted.
+fn run_batches(
+    args: Args,
+    mut input: input::Input,
+    progress: &mut progress::Progress,
+) -> Result<()> {
+    let mut batch_size = args.batch_size.unwrap_or_else(|| {
         // Default to a reasonable batch size based on open file limits, leaving some room for
         // standard streams and other files
-        get_max_open_files().saturating_sub(32)
+        sys::get_max_open_files().saturating_sub(32)
     });
 
-    let stdin = io::stdin();
-    let lines: Vec<String> = stdin.lock().lines()
-        .collect::<std::result::Result<_, _>>()
-        .map_err(|e| XtempError::InvalidUtf8(e))?;
+    let mut batch_dir = if args.cwd_tempdir {
+        Some(create_tempdir(&args)?)
+    } else {
+        None
+    };
 
-    // Create tempfile pool
-    let mut pool: Vec<NamedTempFile> = (0..batch_size)
-        .map(|_| NamedTempFile::new().map_err(|e| XtempError::FailedToWrite(e)))
-        .collect::<Result<_>>()?;
+    // Create tempfile pool, starting with one file so we know how long the paths are. With
+    // --paths it holds the batch's input files instead
+    let mut pool = if args.paths {
+        Vec::new()
+    } else {
+        vec![create_tempfile(&args, batch_dir.as_ref())?]
+    };
+
+    let words = command_words(
+        &args.command,
+        args.replstr(),
+        args.record_replstr.as_deref(),
+        args.replstr_escape.as_deref(),
+        (args.list && args.replstr.is_none()).then_some(LIST_REPLSTR.as_ref()),
+    );
+
+    // Make sure a full batch of tempfile arguments fits within ARG_MAX, and each word they're
+    // joined into within the limit on a single argument
+    let tempfile_slots = if args.list || args.env_name.is_some() {
+        0
+    } else {
+        words.iter().filter(|w| matches!(w, Word::Tempfiles)).count()
+    };
+    let joined = if args.list { Vec::new() } else { joined_words(&args, &words) };
+    let mut per_record = 0;
+    if !args.list && !args.paths && !args.no_run {
+        let (cost, len) = (sys::arg_cost(&pool[0].arg), pool[0].arg.len());
+        let budget = get_arg_budget(&args, &args.command);
+        if let Some(max) = get_max_batch_size(budget, tempfile_slots, &joined, cost, len) {
+            if max == 0 || args.batch_size.is_some_and(|n| n > max) {
+                return Err(XtempError::ArgsTooLong(batch_size, max));
+            }
+            batch_size = batch_size.min(max);
+        }
+        per_record = tempfile_slots * cost + joined.iter().map(|j| len + j.sep).sum::<usize>();
+    }
+    // Lines passed as arguments vary in length, so batches of them are cut short as they fill
+    // up instead: with --paths, where the lines take the place of tempfiles, and with -I
+    let record_slots = words.iter().filter(|w| matches!(w, Word::Records)).count();
+    let arg_budget = if args.paths {
+        let slots = tempfile_slots + record_slots;
+        (slots > 0 || !joined.is_empty()).then_some((0, slots, joined))
+    } else {
+        (record_slots > 0).then_some((per_record, record_slots, Vec::new()))
+    };
+    let arg_budget = arg_budget.map(|(per_record, slots, joined)| input::ArgBudget {
+        bytes: get_arg_budget(&args, &args.command),
+        per_record,
+        slots,
+        joined,
+    });
 
     // Maybe create list file
-    let mut list = if args.list {
-        Some(NamedTempFile::new().map_err(|e| XtempError::FailedToWrite(e))?)
+    let mut lists = if args.list {
+        vec![create_tempfile(&args, batch_dir.as_ref())?]
+    } else {
+        Vec::new()
+    };
+
+    // With -s we know how large a batch can get, so fail before starting if one won't fit
+    if let Some(max_bytes) = args.max_bytes.filter(|_| !args.paths && args.on_disk()) {
+        check_space(batch_dir.as_ref(), max_bytes)?;
+    }
+
+    let template = match (&args.template, &args.file_format) {
+        (Some(path), _) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| XtempError::FailedToOpen(path.display().to_string(), e))?;
+            Some(template::Template::parse(&text))
+        }
+        (None, Some(format)) => Some(template::Template::parse(format)),
+        (None, None) => None,
+    };
+
+    let mut worker = if args.persistent {
+        let list_path = lists.first().map(|l| l.path());
+        let argv = args.command.iter().map(OsString::as_os_str);
+        let command = build_command(&args, argv, None, list_path, batch_dir.as_ref());
+        let worker = worker::Worker::spawn(command)?;
+        log::info(&format!("started persistent command {}", args.command[0].to_string_lossy()));
+        Some(worker)
     } else {
         None
     };
 
-    for chunk in lines.chunks(batch_size) {
-        let mut file_paths = Vec::new();
-
-        // Reuse temp files from the pool
-        for (i, line) in chunk.iter().enumerate() {
-            let tmpfile = &mut pool[i];
-            let file = tmpfile.as_file_mut();
-
-            // TODO DRY
-            file.set_len(0).map_err(|e| XtempError::FailedToWrite(e))?;
-            file.seek(SeekFrom::Start(0)).map_err(|e| XtempError::FailedToWrite(e))?;
-            if args.keep_newlines {
-                writeln!(file, "{}", line).map_err(|e| XtempError::FailedToWrite(e))?;
-            } else {
-                write!(file, "{}", line).map_err(|e| XtempError::FailedToWrite(e))?;
-            }
-            file.flush().map_err(|e| XtempError::FailedToWrite(e))?;
-            file_paths.push(tmpfile.path().to_path_buf());
-        }
-
-        // Build command with file arguments
-        let tempfile_args = match list {
-            Some(ref mut list_tmpfile) => {
-                // Write temp file paths to the list file
-                let file = list_tmpfile.as_file_mut();
-                file.set_len(0).map_err(|e| XtempError::FailedToWrite(e))?;
-                file.seek(SeekFrom::Start(0)).map_err(|e| XtempError::FailedToWrite(e))?;
-                for path in &file_paths {
-                    writeln!(file, "{}", path.display())
-                        .map_err(|e| XtempError::FailedToWrite(e))?;
+    // Where each batch's --cgroup goes, until one fails to be created
+    #[cfg(target_os = "linux")]
+    let mut cgroup_parent = match args.cgroup_parent {
+        Some(ref dir) => Some(dir.clone()),
+        None if args.cgroup => match cgroup::own() {
+            Ok(dir) if args.cgroup_memory_max.is_none() && args.cgroup_cpu_max.is_none() => {
+                Some(dir)
+            }
+            // Limits need controllers enabled in the parent, which xtemp can't stay in
+            Ok(dir) => match cgroup::leave(&dir) {
+                Ok(()) => Some(dir),
+                Err(e) => {
+                    log::error(&format!(
+                        "can't move xtemp out of its cgroup {} for the limits, so running \
+                         without (try --cgroup-parent): {}",
+                        dir.display(),
+                        e
+                    ));
+                    None
+                }
+            },
+            Err(e) => {
+                log::error(&format!("can't find xtemp's cgroup, so running without: {}", e));
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(target_os = "linux")]
+    let cgroup_limits =
+        cgroup::Limits { memory: args.cgroup_memory_max, cpus: args.cgroup_cpu_max };
+
+    #[cfg(unix)]
+    let mut remote = if args.ssh.is_empty() {
+        None
+    } else {
+        Some(remote::Hosts::connect(&args.ssh, args.keep)?)
+    };
+
+    #[cfg(unix)]
+    let mut script = match args.script {
+        Some(ref path) => {
+            Some(script::ScriptWriter::create(path.as_deref()).map_err(XtempError::FailedToWrite)?)
+        }
+        None => None,
+    };
+
+    let mut limits = input::BatchLimits {
+        records: if args.adaptive { 1 } else { batch_size },
+        bytes: args.max_bytes,
+        newline: args.keep_newlines,
+        group_by: args.group_by.map(|field| input::GroupBy {
+            field: field as usize,
+            colsep: args.colsep.clone(),
+        }),
+        arg_budget,
+    };
+    let mut state = match args.state_dir {
+        Some(ref dir) => Some(
+            state::State::open(dir)
+                .map_err(|e| XtempError::FailedToOpen(dir.display().to_string(), e))?,
+        ),
+        None => None,
+    };
+    // Tempfiles for the output of each batch with --then
+    let mut then_pool = Vec::new();
+    // The batch number of the last batch and the line number of the next line read
+    let (mut batch, mut lineno) = match args.replay_from {
+        Some((batch, lineno)) => (batch - 1, lineno),
+        None => (0, 1),
+    };
+    // With --double-buffer, the second set of tempfiles, and the next batch once it's been
+    // written to them
+    let mut spare = Vec::new();
+    let mut next = None;
+    // With --ssh, the batches running on the hosts, and the tempfiles of those that are done
+    #[cfg(unix)]
+    let mut running: Vec<Running> = Vec::new();
+    #[cfg(unix)]
+    let mut free_pools = Vec::new();
+    // Set once no more batches are to be started, leaving only those running to finish
+    let mut exhausted = false;
+    loop {
+        // Nothing's going to see the output of any more batches
+        if !args.ignore_sigpipe && sys::output_closed() {
+            return Err(XtempError::OutputClosed);
+        }
+        // With --ssh, wait for a host to finish its batch once they all have one, or once there
+        // are no more to start
+        #[cfg(unix)]
+        if let Some(ref mut hosts) = remote {
+            if !running.is_empty() && (hosts.all_busy() || exhausted) {
+                let (mut done, status, usage) = wait_for_any(&mut running)?;
+                hosts.release(done.host);
+                // What's logged from here on is about this batch again
+                status::batch_started(done.batch);
+                let captured = match (done.reader.take(), done.then_outputs.take()) {
+                    (Some(reader), Some((stdout, stderr))) => {
+                        let output = reader.join().expect("output reader panicked").map_err(|e| {
+                            XtempError::SubprocessFailed(format!("failed to read output: {}", e))
+                        })?;
+                        Some(Captured { output, stdout, stderr })
                     }
-                file.flush().map_err(|e| XtempError::FailedToWrite(e))?;
-                vec![escape(list_tmpfile.path().to_string_lossy()).to_string()]
+                    _ => None,
+                };
+                let finished = Finished {
+                    batch: done.batch,
+                    chunk: &done.chunk,
+                    lines: done.lines,
+                    files: &done.pool[..done.chunk.len()],
+                    lists: &[],
+                    code: sys::exit_code(status),
+                    started: done.started,
+                    usage,
+                    spawn_retries: done.spawn_retries,
+                    captured,
+                    job: done.job.take(),
+                    job_dir: done.job_dir.take(),
+                    checksums: std::mem::take(&mut done.checksums),
+                    retired: std::mem::take(&mut done.retired),
+                    retired_dir: None,
+                };
+                let (dir, then_pool) = (batch_dir.as_ref(), &mut then_pool);
+                finish_batch(&args, finished, then_pool, dir, progress, &mut state)?;
+                if !args.fresh_files() {
+                    free_pools.push(std::mem::take(&mut done.pool));
+                }
+                if args.adaptive {
+                    let (elapsed, target) = (done.started.elapsed(), args.target_duration);
+                    let size = limits.records;
+                    limits.records = next_adaptive_size(size, elapsed, target, batch_size);
+                }
+                continue;
             }
-            None => {
-                // Pass temp files directly
-                file_paths
+        }
+        if exhausted {
+            break;
+        }
+        let prefetched = next.is_some();
+        // With --group-by each entry of the chunk can hold several lines, so they're counted too
+        let ((mut chunk, mut counts), mut skipped) = match next.take() {
+            Some(next) => {
+                std::mem::swap(&mut pool, &mut spare);
+                next?
+            }
+            None => (input.next_batch(&limits)?, Vec::new()),
+        };
+        if status::draining() {
+            // Keep what was read so that the run can be picked up where it stopped
+            let buffered = input.take_buffered();
+            let lines = counts.iter().sum::<usize>() + buffered.len();
+            if lines > 0 {
+                rejects::add(&chunk, args.null)?;
+                rejects::add(&buffered, args.null)?;
+                log::error(&format!("stopping with {} lines read but not run", lines));
+            }
+            exhausted = true;
+            continue;
+        }
+        if chunk.is_empty() {
+            exhausted = true;
+            continue;
+        }
+        batch += 1;
+        // With --fresh-files, set aside the previous batch's files until this batch is done
+        let mut retired = Vec::new();
+        let mut retired_dir = None;
+        if args.fresh_files() && batch > 1 {
+            retired = std::mem::take(&mut pool);
+            if batch_dir.is_some() {
+                retired_dir = batch_dir.replace(create_tempdir(&args)?);
+            }
+            // New list files are made as they're needed
+            retired.append(&mut lists);
+        }
+        // With --ssh, the batch before may have taken the tempfiles along to its host
+        #[cfg(unix)]
+        if pool.is_empty() {
+            pool = free_pools.pop().unwrap_or_default();
+        }
+
+        if args.paths {
+            pool = chunk
+                .iter()
+                .map(|line| pool::PoolFile::existing(input::strip_delimiter(line, args.null)))
+                .collect();
+        }
+        // With --stdin-field, the field taken out of each line for stdin, and what's left of them
+        let (mut stdin_fields, mut remainders): (Vec<_>, Vec<_>) = match args.stdin_field {
+            Some(field) => chunk
+                .iter()
+                .map(|line| {
+                    input::take_field(line, field as usize, args.colsep.as_deref(), args.null)
+                })
+                .unzip(),
+            None => (Vec::new(), Vec::new()),
+        };
+        if !args.paths && !prefetched {
+            // Reuse temp files from the pool
+            let (dir, template) = (batch_dir.as_ref(), template.as_ref());
+            let lines = if args.stdin_field.is_some() { &remainders } else { &chunk };
+            skipped = fill_pool(&args, template, dir, &mut pool, batch, lineno, lines)?;
+        }
+        let first_lineno = lineno;
+        lineno += counts.iter().sum::<usize>();
+        if !skipped.is_empty() {
+            remove_skipped(&mut counts, &skipped);
+            if args.stdin_field.is_some() {
+                remove_skipped(&mut stdin_fields, &skipped);
+                remove_skipped(&mut remainders, &skipped);
+            }
+            rejects::add(&remove_skipped(&mut chunk, &skipped), args.null)?;
+            log::error(&format!("left {} lines out of the batch", skipped.len()));
+            if chunk.is_empty() {
+                continue;
+            }
+        }
+        // The lines as they go in the tempfiles
+        let lines = if args.stdin_field.is_some() { &remainders } else { &chunk };
+        // The command would see any files left over from a larger batch in --cwd-tempdir's
+        // directory, so remove them
+        if batch_dir.is_some() {
+            pool.truncate(chunk.len());
+        }
+        let files = &pool[..chunk.len()];
+
+        // With --list-split each list file holds at most that many tempfiles
+        let mut parts = 0;
+        if args.list {
+            let per_list = args.list_split.map_or(files.len(), |n| n as usize);
+            for part in files.chunks(per_list) {
+                if parts == lists.len() {
+                    lists.push(create_tempfile(&args, batch_dir.as_ref())?);
+                }
+                let list_tmpfile = &mut lists[parts];
+                list_tmpfile.overwrite(args.fsync, |file| {
+                    let mut file = io::BufWriter::new(file);
+                    part.iter().try_for_each(|f| {
+                        file.write_all(f.arg.as_encoded_bytes()).and_then(|_| writeln!(file))
+                    })?;
+                    file.flush()
+                })?;
+                log::debug(&format!("wrote list to {}", list_tmpfile.path().display()));
+                parts += 1;
+            }
+        }
+        if batch_dir.is_some() {
+            lists.truncate(parts);
+        }
+        let lists = &lists[..parts];
+        // The tempfiles that the command refers to: the list files, or the files themselves
+        let tempfiles = if args.list { lists } else { files };
+        // With --env-name the paths go in the environment rather than the arguments
+        let env_paths = args.env_name.as_ref().map(|name| {
+            let paths: Vec<_> = tempfiles.iter().map(|f| f.arg.as_os_str()).collect();
+            (name.as_str(), paths.join(args.env_separator.as_os_str()))
+        });
+        let arg_files = if env_paths.is_some() { &[] } else { tempfiles };
+
+        if args.fsync {
+            sys::sync_parent_dir(files[0].path()).map_err(XtempError::FailedToWrite)?;
+        }
+
+        let list_path = lists.first().map(|l| l.path());
+        // What each run of the command is given: with --list-split it runs once for each list
+        let runs: Vec<(&[pool::PoolFile], Option<&Path>)> = match args.list_split {
+            Some(_) => lists.chunks(1).map(|list| (list, Some(list[0].path()))).collect(),
+            None => vec![(arg_files, list_path)],
+        };
+        // The lines as -I passes them
+        let records: Vec<&str> = match args.record_replstr {
+            Some(_) => lines.iter().map(|line| input::strip_delimiter(line, args.null)).collect(),
+            None => Vec::new(),
+        };
+
+        if args.no_run {
+            print_paths(tempfiles)?;
+            continue;
+        }
+
+        #[cfg(unix)]
+        if let Some(ref mut script) = script {
+            let cwd = match batch_dir {
+                Some(ref dir) => Some(dir.path().into()),
+                None => {
+                    args.cwd.as_ref().map(|cwd| OsString::from(replace_batch_number(cwd, batch)))
+                }
+            };
+            let mut env = vec![("XTEMP_BATCH".as_ref(), OsString::from(batch.to_string()).into())];
+            if let Some(path) = list_path {
+                env.push(("XTEMP_LIST".as_ref(), path.as_os_str().into()));
+            }
+            if let Some((name, ref paths)) = env_paths {
+                env.push((name.as_ref(), paths.as_os_str().into()));
+            }
+            env.extend(args.env.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str().into())));
+            let joined = join_files(&words, arg_files, |f| &f.arg);
+            script
+                .write(&script::Invocation {
+                    command: expand_words(&words, arg_files, |f| &f.arg, &records, &joined)
+                        .collect(),
+                    env,
+                    cwd: cwd.as_deref(),
+                    stdout: args.stdout_file.as_ref().map(|p| replace_batch_number(p, batch)),
+                    stderr: args.stderr_file.as_ref().map(|p| replace_batch_number(p, batch)),
+                })
+                .map_err(XtempError::FailedToWrite)?;
+            continue;
+        }
+
+        let checksums =
+            if args.verify { checksum_files(files.iter().chain(lists))? } else { Vec::new() };
+
+        let job_dir = job_dir(&args, batch);
+        // With --list-split the job shows the command's first run
+        let arg_files = runs[0].0;
+        let joined = match job_dir {
+            Some(_) => join_files(&words, arg_files, |f| &f.arg),
+            None => Vec::new(),
+        };
+        let job = match job_dir {
+            Some(ref dir) => {
+                let tempfiles = tempfiles
                     .iter()
-                    .map(|p| escape(p.to_string_lossy()).to_string())
-                    .collect()
+                    .map(|f| {
+                        let size = std::fs::metadata(f.path()).map_or(f.size(), |m| m.len());
+                        (f.path().to_path_buf(), size)
+                    })
+                    .collect();
+                let job = job::Job {
+                    batch,
+                    command: expand_words(&words, arg_files, |f| &f.arg, &records, &joined)
+                        .map(|arg| arg.to_string_lossy().into_owned())
+                        .collect(),
+                    lines: (first_lineno, lineno - 1),
+                    tempfiles,
+                    started: SystemTime::now(),
+                    finished: None,
+                    spawn_retries: 0,
+                    usage: None,
+                };
+                std::fs::create_dir_all(dir)
+                    .and_then(|_| std::fs::write(dir.join("input"), chunk.concat()))
+                    .and_then(|_| job.write(dir))
+                    .map_err(XtempError::FailedToWrite)?;
+                Some(job)
             }
+            None => None,
         };
 
-        let full_cmd = resolve_replstr(&args.command, args.replstr.as_deref(), tempfile_args);
+        let lines_in_batch: usize = counts.iter().sum();
+        progress.batch_started(batch, lines_in_batch)?;
+        status::batch_started(batch);
+        if let Some(ref hook) = args.pre_hook {
+            run_hook("pre-hook", hook, files, |cmd| set_batch_env(cmd, &args, batch, list_path))?;
+        }
 
-        let mut child = Command::new(&full_cmd[0])
-            .args(&full_cmd[1..])
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .map_err(|e| XtempError::SubprocessFailed(e.to_string()))?;
+        // Copy the lines (and flush them) before the command starts writing to the same stdout
+        if args.tee {
+            tee_lines(&chunk, args.ignore_sigpipe)?;
+        }
 
-        let status = child.wait().map_err(|_| {
-            XtempError::SubprocessFailed("failed to wait for command".into())
-        })?;
+        let started = Instant::now();
+        let mut captured = None;
+        let mut usage = None;
+        let mut spawn_retries = 0;
+        // With --ssh, the batch's command once it's been started on a host
+        #[cfg(unix)]
+        let mut launched = None;
+        let code = match worker {
+            Some(ref mut worker) => {
+                status::command_started(&args.command[0].to_string_lossy(), tempfiles.len());
+                log::info(&format!("sending {} tempfiles to persistent command", files.len()));
+                worker.run_batch(tempfiles.iter().map(|f| f.arg.as_os_str()))?
+            }
+            None => {
+                let mut code = 0;
+                for &(arg_files, list_path) in &runs {
+                    #[cfg(unix)]
+                    let mut on_host = None;
+                    #[cfg(unix)]
+                    let remote_cmd = match remote {
+                        Some(ref mut hosts) => {
+                            let host = hosts.take().expect("a host is free for the batch");
+                            on_host = Some(host);
+                            let host = hosts.host(host);
+                            Some(remote_command(&args, &words, host, batch, files, &records)?)
+                        }
+                        None => None,
+                    };
+                    #[cfg(not(unix))]
+                    let remote_cmd = None;
+                    let mut command = match remote_cmd {
+                        Some(command) => command,
+                        None => {
+                            let program = args.command[0].to_string_lossy();
+                            status::command_started(&program, tempfiles.len());
+                            log::info(&format!(
+                                "running {} with {} tempfiles",
+                                program,
+                                files.len()
+                            ));
+                            let joined = join_files(&words, arg_files, |f| &f.arg);
+                            let argv =
+                                expand_words(&words, arg_files, |f| &f.arg, &records, &joined);
+                            let batch_dir = batch_dir.as_ref();
+                            let mut command =
+                                build_command(&args, argv, Some(batch), list_path, batch_dir);
+                            if let Some((name, ref paths)) = env_paths {
+                                command.env(name, paths);
+                            }
+                            command
+                        }
+                    };
+                    #[cfg(target_os = "linux")]
+                    let cgroup = match cgroup_parent {
+                        Some(ref parent) => {
+                            let name = format!("xtemp-{}-{}", process::id(), batch);
+                            match cgroup::Cgroup::create(parent, &name, &cgroup_limits) {
+                                Ok(cgroup) => {
+                                    cgroup.apply(&mut command);
+                                    Some(cgroup)
+                                }
+                                Err(e) => {
+                                    log::error(&format!(
+                                        "failed to create a cgroup in {}, so running without: {}",
+                                        parent.display(),
+                                        e
+                                    ));
+                                    cgroup_parent = None;
+                                    None
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+                    // Without --stdin-content the command mustn't compete with us for our own stdin
+                    let stdin = args.stdin_content || args.stdin_field.is_some();
+                    command.stdin(if stdin { Stdio::piped() } else { Stdio::null() });
+                    let (stdout, stderr) = open_output_files(&args, batch, false)?;
+                    // With --then the command's output is captured, and the output files are for
+                    // the next stage
+                    let (stdout, then_outputs) = match args.then {
+                        Some(_) => {
+                            let then_stderr = stderr
+                                .as_ref()
+                                .map(File::try_clone)
+                                .transpose()
+                                .map_err(XtempError::FailedToWrite)?;
+                            (Stdio::piped(), Some((stdout, then_stderr)))
+                        }
+                        None => (output_stdio(stdout), None),
+                    };
+                    command.stdout(stdout).stderr(output_stdio(stderr));
+                    let mut child;
+                    (child, spawn_retries) = spawn_with_backoff(&args, &mut command)?;
+                    #[cfg(unix)]
+                    {
+                        status::child_started(child.id());
+                        if args.setsid {
+                            status::group_started(child.id());
+                        }
+                    }
+
+                    // Read the output on another thread so that the command can't block on it
+                    // while we're writing its stdin
+                    let reader = child.stdout.take().map(|mut out| {
+                        std::thread::spawn(move || {
+                            let mut output = Vec::new();
+                            out.read_to_end(&mut output).map(|_| output)
+                        })
+                    });
 
-        if !status.success() {
-            return Err(XtempError::SubprocessFailed(format!(
-                "command exited with code {}",
-                status.code().unwrap_or(-1)
-            )));
+                    if let Some(stdin) = child.stdin.take() {
+                        let content =
+                            if args.stdin_field.is_some() { &stdin_fields } else { &chunk };
+                        write_stdin_content(stdin, content, args.null)?;
+                    }
+                    // It's finished off once it's done, while the other hosts get batches
+                    #[cfg(unix)]
+                    if let Some(host) = on_host {
+                        launched = Some((host, child, reader, then_outputs));
+                        break;
+                    }
+
+                    let waited = std::thread::scope(|scope| {
+                        // Only once per batch, however many times the command runs on it
+                        let prefetch = (args.double_buffer && next.is_none()).then(|| {
+                            scope.spawn(|| {
+                                let (chunk, counts) = input.next_batch(&limits)?;
+                                let (dir, template) = (batch_dir.as_ref(), template.as_ref());
+                                let (pool, batch) = (&mut spare, batch + 1);
+                                let skipped =
+                                    fill_pool(&args, template, dir, pool, batch, lineno, &chunk)?;
+                                Ok(((chunk, counts), skipped))
+                            })
+                        });
+                        let waited = sys::wait_with_usage(&mut child);
+                        next = prefetch.map(|handle| handle.join().expect("prefetch panicked"));
+                        waited
+                    });
+                    let status;
+                    (status, usage) = waited.map_err(|_| {
+                        XtempError::SubprocessFailed("failed to wait for command".into())
+                    })?;
+                    #[cfg(unix)]
+                    {
+                        status::child_finished(child.id());
+                        if args.setsid {
+                            status::group_finished(child.id());
+                        }
+                    }
+                    // Kills whatever the command left in its cgroup
+                    #[cfg(target_os = "linux")]
+                    drop(cgroup);
+                    if let (Some(reader), Some((stdout, stderr))) = (reader, then_outputs) {
+                        let output = reader.join().expect("output reader panicked").map_err(|e| {
+                            XtempError::SubprocessFailed(format!("failed to read output: {}", e))
+                        })?;
+                        captured = Some(Captured { output, stdout, stderr });
+                    }
+                    code = sys::exit_code(status);
+                    if code != 0 || status::draining() {
+                        break;
+                    }
+                }
+                code
+            }
+        };
+        #[cfg(unix)]
+        if let Some((host, child, reader, then_outputs)) = launched {
+            running.push(Running {
+                host,
+                child: Some(child),
+                reader,
+                then_outputs,
+                batch,
+                chunk,
+                lines: lines_in_batch,
+                pool: std::mem::take(&mut pool),
+                started,
+                spawn_retries,
+                job,
+                job_dir,
+                checksums,
+                retired,
+                null: args.null,
+            });
+            continue;
+        }
+
+        let finished = Finished {
+            batch,
+            chunk: &chunk,
+            lines: lines_in_batch,
+            files,
+            lists,
+            code,
+            started,
+            usage,
+            spawn_retries,
+            captured,
+            job,
+            job_dir,
+            checksums,
+            retired,
+            retired_dir,
+        };
+        let (dir, then_pool) = (batch_dir.as_ref(), &mut then_pool);
+        if let Err(e) = finish_batch(&args, finished, then_pool, dir, progress, &mut state) {
+            // Nor is the batch read ahead with --double-buffer going to be run
+            if let Some(Ok(((ref next, _), _))) = next {
+                rejects::add(next, args.null)?;
+            }
+            return Err(e);
+        }
+        if args.adaptive {
+            let elapsed = started.elapsed();
+            limits.records =
+                next_adaptive_size(limits.records, elapsed, args.target_duration, batch_size);
         }
     }
+
+    if let Some(worker) = worker {
+        worker.finish()?;
+    }
+    #[cfg(unix)]
+    if let Some(script) = script {
+        script.finish().map_err(XtempError::FailedToWrite)?;
+    }
+    if status::past_deadline() {
+        return Err(XtempError::DeadlineReached(batch));
+    }
+    if status::draining() {
+        return Err(XtempError::Terminated(batch));
+    }
     Ok(())
 }
@@ -0,0 +1,90 @@
+//! Renders the man page, adding the FILES, ENVIRONMENT, SIGNALS and EXAMPLES sections to it.
+
+use clap_mangen::Man;
+use std::io::{self, Write};
+
+const FILES: &str = r#".SH FILES
+.TP
+\fI~/.config/xtemp/config.toml\fR
+Default options, one long option name per key (e.g. \fBbatch\-size = 500\fR). Boolean options
+are enabled with \fBtrue\fR and arrays repeat an option. \fB$XDG_CONFIG_HOME\fR is used instead of
+\fI~/.config\fR if set.
+.SH ENVIRONMENT
+.TP
+\fBXTEMP_CONFIG\fR
+Path of the config file to read instead of the default.
+.TP
+\fBXTEMP_OPTS\fR
+Default options, split like shell words. These take precedence over the config file, and options
+given on the command line take precedence over both.
+"#;
+
+const SIGNALS: &str = r#".SH SIGNALS
+.TP
+\fBSIGUSR1\fR
+Print the current batch number, the number of lines processed, the number of failed batches, the
+elapsed time and the running command to stderr.
+.TP
+\fBSIGTERM\fR
+Stop reading input and let the batch in flight finish, then exit with status 143. Lines already
+read but not yet dispatched are discarded.
+.TP
+\fBSIGINT\fR
+With \fB\-\-setsid\fR, passed on to the process group of the command in flight; no further
+batches are started.
+"#;
+
+const EXAMPLES: &str = r#".SH EXAMPLES
+Calculate the MD5 of each line of input, rather than of the input as a whole:
+.PP
+.RS
+.nf
+$ printf 'foo\enbar\en' | xtemp md5sum
+acbd18db4cc2f85cedef654fccc4a4d8  /tmp/.tmpn6rIRI
+37b51d194a7513e45b56f6524f2d51f2  /tmp/.tmpnJk5rQ
+.fi
+.RE
+.PP
+Use \fB\-J\fR to place the tempfiles somewhere other than the end of the command. Here
+\fI/dev/null\fR always follows them so that grep prints file names even for a batch of one:
+.PP
+.RS
+.nf
+$ xtemp \-J {} \-\- grep \-c pattern {} /dev/null < records.txt
+.fi
+.RE
+.PP
+Use \fB\-l\fR to pass a single file listing the tempfile paths, for commands that read their
+inputs from a list:
+.PP
+.RS
+.nf
+$ xtemp \-l \-J {} \-\- tar \-rf records.tar \-T {} < records.txt
+.fi
+.RE
+.PP
+At most 100 lines, and at most 1MiB of data, per invocation:
+.PP
+.RS
+.nf
+$ xtemp \-n 100 \-s 1M sha256sum < records.txt
+.fi
+.RE
+"#;
+
+/// Renders the man page for `cmd`, adding sections that can't be derived from the CLI
+/// definition.
+pub fn render(cmd: clap::Command, w: &mut dyn Write) -> io::Result<()> {
+    let man = Man::new(cmd);
+    man.render_title(w)?;
+    man.render_name_section(w)?;
+    man.render_synopsis_section(w)?;
+    man.render_description_section(w)?;
+    man.render_options_section(w)?;
+    man.render_subcommands_section(w)?;
+    w.write_all(FILES.as_bytes())?;
+    w.write_all(SIGNALS.as_bytes())?;
+    w.write_all(EXAMPLES.as_bytes())?;
+    man.render_version_section(w)?;
+    man.render_authors_section(w)
+}
@@ -0,0 +1,183 @@
+//! Tempfiles that batches are written to, which are reused from one batch to the next unless
+//! --fresh-files is given. With --paths the files are the input's own, and aren't written.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::{log, Result, XtempError};
+
+/// How many times --on-write-error retry tries writing a tempfile again
+const WRITE_RETRIES: u32 = 5;
+
+/// How long --on-write-error retry waits before trying again the first time, doubling each time
+const WRITE_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// What to do when writing a tempfile fails, for --on-write-error
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnWriteError {
+    /// Leave the line out of its batch
+    Skip,
+    /// Try again a few times, waiting longer each time, before giving up
+    Retry,
+    /// Stop with an error
+    Abort,
+}
+
+/// What a pool file's contents are kept in
+enum Backing {
+    Tempfile(NamedTempFile),
+    /// A file in memory, with --memfd
+    #[cfg(target_os = "linux")]
+    Memfd(File),
+    /// An existing file with --paths
+    Existing,
+}
+
+/// A tempfile along with the arguments that refer to it, which are worked out once rather than
+/// for every batch.
+pub struct PoolFile {
+    file: Backing,
+    path: PathBuf,
+    /// Argument referring to the file: its full path, or with --cwd-tempdir just its name
+    pub arg: OsString,
+    /// Size of what was last written to the file
+    size: u64,
+}
+
+impl PoolFile {
+    pub fn new(file: NamedTempFile, relative: bool) -> Self {
+        let arg = tempfile_arg(file.path(), relative).to_owned();
+        PoolFile { path: file.path().to_path_buf(), file: Backing::Tempfile(file), arg, size: 0 }
+    }
+
+    /// Refers to a memfd by `path`, its /proc/self/fd path.
+    #[cfg(target_os = "linux")]
+    pub fn memfd(file: File, path: PathBuf) -> Self {
+        PoolFile { arg: path.clone().into(), path, file: Backing::Memfd(file), size: 0 }
+    }
+
+    /// Refers to an existing file, which is passed on exactly as given and never written to or
+    /// removed.
+    pub fn existing(path: &str) -> Self {
+        PoolFile { file: Backing::Existing, path: path.into(), arg: path.into(), size: 0 }
+    }
+
+    fn file_mut(&mut self) -> Option<&mut File> {
+        match self.file {
+            Backing::Tempfile(ref mut file) => Some(file.as_file_mut()),
+            #[cfg(target_os = "linux")]
+            Backing::Memfd(ref mut file) => Some(file),
+            Backing::Existing => None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns a checksum of the file's contents as they are on disk, for --verify.
+    pub fn checksum(&self) -> io::Result<u64> {
+        let mut file = File::open(&self.path)?;
+        let mut hasher = DefaultHasher::new();
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(hasher.finish()),
+                Ok(n) => hasher.write(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Replaces the file's contents with whatever `write` writes to it, syncing them to disk if
+    /// `fsync` is set. Writes go straight to the file, so anything buffered must be flushed by
+    /// `write`.
+    pub fn overwrite(
+        &mut self,
+        fsync: bool,
+        write: impl FnOnce(&mut File) -> io::Result<()>,
+    ) -> Result<()> {
+        let file = self.file_mut().expect("only tempfiles are overwritten");
+        self.size = file
+            .set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write(file))
+            .and_then(|_| if fsync { file.sync_all() } else { Ok(()) })
+            .and_then(|_| file.stream_position())
+            .map_err(XtempError::FailedToWrite)?;
+        Ok(())
+    }
+
+    /// Overwrites the file like `overwrite`, but if that fails, tries again or gives up on the
+    /// file as `policy` says. Returns whether the file was written.
+    pub fn overwrite_with_policy(
+        &mut self,
+        policy: OnWriteError,
+        fsync: bool,
+        write: impl Fn(&mut File) -> io::Result<()>,
+    ) -> Result<bool> {
+        let mut delay = WRITE_RETRY_DELAY;
+        let mut retries = 0;
+        loop {
+            let e = match self.overwrite(fsync, &write) {
+                Ok(()) => return Ok(true),
+                Err(e) => e,
+            };
+            match policy {
+                OnWriteError::Retry if retries < WRITE_RETRIES => {
+                    let path = self.path.display();
+                    log::error(&format!("{} ({}), trying again in {:?}", e, path, delay));
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    retries += 1;
+                }
+                OnWriteError::Skip => {
+                    log::error(&format!("{} ({}), skipping its line", e, self.path.display()));
+                    // Free up whatever was written for the rest of the batch
+                    if let Some(file) = self.file_mut() {
+                        let _ = file.set_len(0);
+                        self.size = 0;
+                    }
+                    return Ok(false);
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+/// Writes a record, followed by a newline if `newline` is set, in as few system calls as
+/// possible: one, unless the record is too large for the kernel to take in one go.
+pub fn write_record(file: &mut File, record: &[u8], newline: bool) -> io::Result<()> {
+    let ending: &[u8] = if newline { b"\n" } else { b"" };
+    let mut bufs = [IoSlice::new(record), IoSlice::new(ending)];
+    let mut bufs = &mut bufs[..];
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match file.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the argument that refers to a tempfile: its full path, or with --cwd-tempdir just
+/// its name.
+fn tempfile_arg(path: &Path, relative: bool) -> &OsStr {
+    match path.file_name() {
+        Some(name) if relative => name,
+        _ => path.as_os_str(),
+    }
+}
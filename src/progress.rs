@@ -0,0 +1,103 @@
+//! Machine-readable progress for --progress-fd: one JSON object per line for each event.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+use crate::{sys, Result, XtempError};
+
+pub struct Progress {
+    out: Option<File>,
+    started: Instant,
+    batches: usize,
+    lines: usize,
+}
+
+impl Progress {
+    /// Reports progress to `out`, or nowhere if it's None.
+    pub fn new(out: Option<File>) -> Self {
+        Progress { out, started: Instant::now(), batches: 0, lines: 0 }
+    }
+
+    /// Reports that a batch of `lines` lines is about to run.
+    pub fn batch_started(&mut self, batch: usize, lines: usize) -> Result<()> {
+        self.batches = batch;
+        self.lines += lines;
+        self.emit(&format!(r#"{{"event":"batch_started","batch":{},"lines":{}}}"#, batch, lines))
+    }
+
+    /// Reports that the command finished a batch, successfully or not, with the resources it
+    /// used if they're known.
+    pub fn batch_finished(
+        &mut self,
+        batch: usize,
+        code: i32,
+        elapsed: f64,
+        usage: Option<&sys::Usage>,
+    ) -> Result<()> {
+        let usage = match usage {
+            Some(usage) => format!(
+                r#","max_rss_kb":{},"user_time":{:.3},"system_time":{:.3}"#,
+                usage.max_rss,
+                usage.user.as_secs_f64(),
+                usage.system.as_secs_f64()
+            ),
+            None => String::new(),
+        };
+        self.emit(&format!(
+            r#"{{"event":"batch_finished","batch":{},"exit_code":{},"elapsed":{:.3}{}}}"#,
+            batch, code, elapsed, usage
+        ))
+    }
+
+    /// Reports how the run ended: a failure event if it failed, followed by the totals. Errors
+    /// writing these are ignored so that they don't hide the outcome itself.
+    pub fn finish(&mut self, result: &Result<()>) {
+        if let Err(e) = result {
+            let _ = self.emit(&format!(
+                r#"{{"event":"failure","batch":{},"error":{}}}"#,
+                self.batches,
+                json_string(&e.to_string())
+            ));
+        }
+        let _ = self.emit(&format!(
+            r#"{{"event":"totals","batches":{},"lines":{},"ok":{},"elapsed":{:.3}}}"#,
+            self.batches,
+            self.lines,
+            result.is_ok(),
+            self.started.elapsed().as_secs_f64()
+        ));
+    }
+
+    fn emit(&mut self, event: &str) -> Result<()> {
+        match self.out {
+            // Write each event in one go so that readers never see part of a line
+            Some(ref mut out) => {
+                out.write_all(format!("{}\n", event).as_bytes()).map_err(XtempError::FailedToWrite)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Quotes a string as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
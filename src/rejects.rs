@@ -0,0 +1,151 @@
+//! The --rejects file, which records that are left out or whose batch fails are appended to as
+//! they were read, so that xtemp can be run again on just those. Records are left out on the
+//! input threads as well as while running batches, so the file is shared by all of them.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use encoding_rs::Encoding;
+
+use crate::{log, transcode, Result, XtempError};
+
+/// The file, and the --from-encoding that records are converted back to before they're added
+static FILE: Mutex<Option<(File, Option<&'static Encoding>)>> = Mutex::new(None);
+
+/// Starts appending rejected records to `path` in `encoding` (UTF-8 if None), or stops adding
+/// them anywhere if it's None.
+pub fn open(path: Option<&Path>, encoding: Option<&'static Encoding>) -> Result<()> {
+    let file = match path {
+        Some(path) => Some((
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| XtempError::FailedToOpen(path.display().to_string(), e))?,
+            encoding,
+        )),
+        None => None,
+    };
+    *FILE.lock().unwrap_or_else(|e| e.into_inner()) = file;
+    Ok(())
+}
+
+/// Returns whether rejected records are being kept.
+pub fn enabled() -> bool {
+    FILE.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// Appends records as they were read, ending each with the delimiter if it doesn't already.
+pub fn add<R: AsRef<[u8]>>(records: &[R], null: bool) -> Result<()> {
+    let mut file = FILE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some((ref mut file, encoding)) = *file else {
+        return Ok(());
+    };
+    let delimiter = if null { b'\0' } else { b'\n' };
+    let mut bytes = Vec::new();
+    for record in records {
+        let record = record.as_ref();
+        bytes.extend_from_slice(record);
+        if record.last() != Some(&delimiter) {
+            bytes.push(delimiter);
+        }
+    }
+    // Write them in one go so that records from different threads don't interleave
+    let written = match encoding {
+        Some(encoding) => {
+            file.write_all(&transcode::encode(encoding, &String::from_utf8_lossy(&bytes)))
+        }
+        None => file.write_all(&bytes),
+    };
+    written.map_err(XtempError::FailedToWrite)
+}
+
+/// Appends a single record in pieces, for one too large to hold in memory. The pieces are kept
+/// in a file of the writer's own until it's finished, and only then copied over with the file
+/// locked, so that no other records end up in the middle of it without their threads having to
+/// wait on the record's input meanwhile. Like `add_logged` it's for the input threads, and logs
+/// errors.
+pub struct Writer {
+    pieces: Option<File>,
+    delimiter: u8,
+    last: Option<u8>,
+    failed: bool,
+}
+
+impl Writer {
+    pub fn new(null: bool) -> Self {
+        let delimiter = if null { b'\0' } else { b'\n' };
+        Writer { pieces: None, delimiter, last: None, failed: false }
+    }
+
+    pub fn add(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() || self.failed {
+            return;
+        }
+        let written = match self.pieces {
+            Some(ref mut pieces) => pieces.write_all(bytes),
+            None => {
+                tempfile::tempfile().and_then(|pieces| self.pieces.insert(pieces).write_all(bytes))
+            }
+        };
+        if let Err(e) = written {
+            log::error(&format!("--rejects: {}", e));
+            self.failed = true;
+        }
+        self.last = bytes.last().copied();
+    }
+
+    /// Ends the record with the delimiter if it doesn't already, and appends it to the file.
+    pub fn finish(mut self) {
+        if self.last.is_some() && self.last != Some(self.delimiter) {
+            self.add(&[self.delimiter]);
+        }
+        let Some(mut pieces) = self.pieces.take().filter(|_| !self.failed) else {
+            return;
+        };
+        let mut file = FILE.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((ref mut file, encoding)) = *file else {
+            return;
+        };
+        let copied = pieces.rewind().and_then(|_| match encoding {
+            Some(encoding) => copy_encoded(&mut pieces, file, encoding),
+            None => io::copy(&mut pieces, file).map(|_| ()),
+        });
+        if let Err(e) = copied {
+            log::error(&format!("--rejects: {}", e));
+        }
+    }
+}
+
+/// Copies UTF-8 from `from` to `to` in `encoding`, a chunk at a time.
+fn copy_encoded(from: &mut File, to: &mut File, encoding: &'static Encoding) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    // The start of a character split between chunks
+    let mut carried = 0;
+    loop {
+        let n = from.read(&mut buf[carried..])?;
+        let end = carried + n;
+        // At the end of the file, whatever's left is malformed and written as such
+        let valid = match std::str::from_utf8(&buf[..end]) {
+            Err(e) if n > 0 && e.error_len().is_none() => e.valid_up_to(),
+            _ => end,
+        };
+        to.write_all(&transcode::encode(encoding, &String::from_utf8_lossy(&buf[..valid])))?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.copy_within(valid..end, 0);
+        carried = end - valid;
+    }
+}
+
+/// Appends a record like `add`, for the input threads, which have no way to fail the run and so
+/// just log the error.
+#[cfg(unix)]
+pub fn add_logged(record: &[u8], null: bool) {
+    if let Err(e) = add(&[record], null) {
+        log::error(&format!("--rejects: {}", e));
+    }
+}
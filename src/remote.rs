@@ -0,0 +1,142 @@
+//! Running batches on other hosts with --ssh. Each host gets a temporary directory that the
+//! batch's tempfiles are copied into before the command runs there. Each host runs a batch at a
+//! time, and batches go to the hosts that are free in turn.
+
+use shell_escape::unix::escape;
+use std::borrow::Cow;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{log, sys, Result, XtempError};
+
+pub struct Host {
+    dest: String,
+    /// Temporary directory on the host that receives the tempfiles
+    dir: String,
+    keep: bool,
+}
+
+impl Host {
+    /// Creates a temporary directory on `dest`, removed again when the host is dropped unless
+    /// `keep` is set.
+    fn connect(dest: &str, keep: bool) -> Result<Self> {
+        let output = ssh(dest, r#"mktemp -d "${TMPDIR:-/tmp}/xtemp.XXXXXXXX""#)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| XtempError::SubprocessFailed(format!("ssh {}: {}", dest, e)))?;
+        let dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || dir.is_empty() {
+            return Err(XtempError::SubprocessFailed(format!(
+                "ssh {}: could not create a temporary directory (exit code {})",
+                dest,
+                sys::exit_code(output.status)
+            )));
+        }
+        log::info(&format!("created {} on {}", dir, dest));
+        Ok(Host { dest: dest.to_string(), dir, keep })
+    }
+
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    /// Copies tempfiles to the host, returning their paths there.
+    pub fn upload<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a Path> + Clone,
+    ) -> Result<Vec<String>> {
+        let status = Command::new("scp")
+            .arg("-q")
+            .arg("--")
+            .args(paths.clone())
+            .arg(format!("{}:{}/", self.dest, self.dir))
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| XtempError::SubprocessFailed(format!("scp: {}", e)))?;
+        if !status.success() {
+            return Err(XtempError::SubprocessFailed(format!(
+                "scp to {} exited with code {}",
+                self.dest,
+                sys::exit_code(status)
+            )));
+        }
+        Ok(paths
+            .into_iter()
+            .map(|path| {
+                let name = path.file_name().unwrap_or(path.as_os_str());
+                format!("{}/{}", self.dir, name.to_string_lossy())
+            })
+            .collect())
+    }
+
+    /// Returns a command that runs `argv` on the host with `env` set. Its output and exit code
+    /// are those of the remote command, or 255 if ssh itself fails.
+    pub fn command(&self, argv: &[&str], env: &[(&str, Cow<str>)]) -> Command {
+        let mut words: Vec<Cow<str>> = vec!["env".into()];
+        for (key, val) in env {
+            words.push(format!("{}={}", key, escape(val.clone())).into());
+        }
+        words.extend(argv.iter().map(|&arg| escape(arg.into())));
+        ssh(&self.dest, &words.join(" "))
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        if !self.keep {
+            let script = format!("rm -rf {}", escape(self.dir.as_str().into()));
+            let _ = ssh(&self.dest, &script).stdin(Stdio::null()).status();
+            log::info(&format!("removed {} on {}", self.dir, self.dest));
+        }
+    }
+}
+
+/// The hosts given with --ssh, which take batches in turn.
+pub struct Hosts {
+    hosts: Vec<Host>,
+    /// Whether each host is running a batch
+    busy: Vec<bool>,
+    next: usize,
+}
+
+impl Hosts {
+    pub fn connect(dests: &[String], keep: bool) -> Result<Self> {
+        let hosts: Vec<_> =
+            dests.iter().map(|dest| Host::connect(dest, keep)).collect::<Result<_>>()?;
+        let busy = vec![false; hosts.len()];
+        Ok(Hosts { hosts, busy, next: 0 })
+    }
+
+    /// Returns whether every host is running a batch.
+    pub fn all_busy(&self) -> bool {
+        self.busy.iter().all(|&busy| busy)
+    }
+
+    /// Takes the next free host to run a batch on, returning its index, or None if they're all
+    /// busy.
+    pub fn take(&mut self) -> Option<usize> {
+        let i = (0..self.hosts.len())
+            .map(|i| (self.next + i) % self.hosts.len())
+            .find(|&i| !self.busy[i])?;
+        self.busy[i] = true;
+        self.next = (i + 1) % self.hosts.len();
+        Some(i)
+    }
+
+    pub fn host(&self, i: usize) -> &Host {
+        &self.hosts[i]
+    }
+
+    /// Frees a host taken with `take` once its batch is done.
+    pub fn release(&mut self, i: usize) {
+        self.busy[i] = false;
+    }
+}
+
+/// Returns a command that runs `script` with the remote shell on `dest`.
+fn ssh(dest: &str, script: &str) -> Command {
+    let mut cmd = Command::new("ssh");
+    cmd.arg("--").arg(dest).arg(script);
+    cmd
+}
@@ -0,0 +1,105 @@
+//! The shell script that --script writes in place of running anything: one line for each
+//! invocation xtemp would have made, with its environment, directory and redirections.
+
+use shell_escape::unix::escape;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::fs::{File, Permissions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// A single command invocation, as recorded by --script.
+pub struct Invocation<'a> {
+    pub command: Vec<&'a OsStr>,
+    pub env: Vec<(&'a OsStr, Cow<'a, OsStr>)>,
+    pub cwd: Option<&'a OsStr>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+/// Quotes a word for the shell as it is, byte for byte. Bytes that aren't UTF-8 are written out
+/// by printf, since $'\xNN' isn't in every sh, and joined to the quoted text around them.
+fn quote(word: &OsStr) -> Cow<'_, str> {
+    let bytes = word.as_bytes();
+    if let Ok(word) = std::str::from_utf8(bytes) {
+        return escape(word.into());
+    }
+    let mut quoted = String::new();
+    let mut invalid = Vec::new();
+    let printf = |quoted: &mut String, invalid: &mut Vec<u8>| {
+        quoted.push_str("\"$(printf '");
+        for byte in invalid.drain(..) {
+            let _ = write!(quoted, "\\{:03o}", byte);
+        }
+        quoted.push_str("')\"");
+    };
+    for chunk in bytes.utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            if !invalid.is_empty() {
+                printf(&mut quoted, &mut invalid);
+            }
+            quoted.push_str(&escape(chunk.valid().into()));
+        }
+        invalid.extend_from_slice(chunk.invalid());
+    }
+    if !invalid.is_empty() {
+        printf(&mut quoted, &mut invalid);
+    }
+    quoted.into()
+}
+
+/// Writes a shell script that performs the invocations xtemp would otherwise have made.
+pub struct ScriptWriter {
+    out: BufWriter<Box<dyn Write>>,
+}
+
+impl ScriptWriter {
+    /// Starts a script in `path`, or on stdout if not given.
+    pub fn create(path: Option<&Path>) -> io::Result<Self> {
+        let out: Box<dyn Write> = match path {
+            Some(path) => {
+                let file = File::create(path)?;
+                file.set_permissions(Permissions::from_mode(0o755))?;
+                Box::new(file)
+            }
+            None => Box::new(io::stdout()),
+        };
+        let mut out = BufWriter::new(out);
+        writeln!(out, "#!/bin/sh")?;
+        writeln!(out, "# Generated by xtemp")?;
+        writeln!(out, "set -e")?;
+        Ok(ScriptWriter { out })
+    }
+
+    pub fn write(&mut self, inv: &Invocation) -> io::Result<()> {
+        let mut words: Vec<Cow<str>> = Vec::new();
+        for (key, val) in &inv.env {
+            words.push(format!("{}={}", key.to_string_lossy(), quote(val)).into());
+        }
+        words.extend(inv.command.iter().map(|arg| quote(arg)));
+        if let Some(ref path) = inv.stdout {
+            words.push(format!(">{}", escape(path.into())).into());
+        }
+        if let Some(ref path) = inv.stderr {
+            // Redirecting both streams to the same file must not truncate it twice
+            if inv.stdout.as_ref() == Some(path) {
+                words.push("2>&1".into());
+            } else {
+                words.push(format!("2>{}", escape(path.into())).into());
+            }
+        }
+        match inv.cwd {
+            Some(cwd) => {
+                writeln!(self.out, "(cd {} && {})", quote(cwd), words.join(" "))
+            }
+            None => writeln!(self.out, "{}", words.join(" ")),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,64 @@
+//! Records processed successfully, kept in --state-dir so that later runs can skip them with
+//! --skip-processed. Each record is stored as a hash, one per line, in the directory's
+//! "processed" file.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::input::strip_delimiter;
+
+const PROCESSED: &str = "processed";
+
+/// Where the hashes of a run's processed records are added
+pub struct State {
+    file: File,
+}
+
+impl State {
+    /// Opens the state in `dir`, creating it if need be.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let file = File::options().create(true).append(true).open(dir.join(PROCESSED))?;
+        Ok(State { file })
+    }
+
+    /// Adds a successful batch's records. With --group-by an entry can hold several records,
+    /// each of which is added.
+    pub fn add(&mut self, batch: &[String], null: bool) -> io::Result<()> {
+        let mut lines = String::new();
+        for record in batch.iter().flat_map(|entry| entry.split_inclusive(delimiter(null))) {
+            lines.push_str(&format!("{:016x}\n", hash(strip_delimiter(record, null))));
+        }
+        // Add each batch in one go so that runs sharing the state don't interleave
+        self.file.write_all(lines.as_bytes())
+    }
+}
+
+/// Reads the hashes of the records processed so far by runs with the state in `dir`.
+pub fn load(dir: &Path) -> io::Result<HashSet<u64>> {
+    match std::fs::read_to_string(dir.join(PROCESSED)) {
+        Ok(text) => {
+            Ok(text.lines().filter_map(|line| u64::from_str_radix(line, 16).ok()).collect())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn delimiter(null: bool) -> char {
+    if null {
+        '\0'
+    } else {
+        '\n'
+    }
+}
+
+/// Hashes a record, without its delimiter, with 64-bit FNV-1a, which unlike std's hashers is
+/// guaranteed to stay the same from one build to the next.
+pub fn hash(record: &str) -> u64 {
+    record.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
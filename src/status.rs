@@ -0,0 +1,190 @@
+//! What xtemp is up to, for the report printed on SIGUSR1, and whether it's been asked to stop
+//! with SIGTERM or by the --deadline.
+
+// There's no signal to ask for the report with elsewhere
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+struct Status {
+    started: Option<Instant>,
+    batch: usize,
+    lines: usize,
+    failures: usize,
+    command: Option<String>,
+}
+
+static STATUS: Mutex<Status> =
+    Mutex::new(Status { started: None, batch: 0, lines: 0, failures: 0, command: None });
+
+/// Set on SIGTERM: no further batches are started
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Set, along with DRAINING, once the --deadline has passed
+static PAST_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// The commands in flight, of which there's one for each host with --ssh
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// With --setsid, the process group of the command in flight, or 0 if there's none
+static GROUP: AtomicU32 = AtomicU32::new(0);
+
+fn update(f: impl FnOnce(&mut Status)) {
+    if let Ok(mut status) = STATUS.lock() {
+        f(&mut status);
+    }
+}
+
+/// Starts keeping track. On Unix, SIGUSR1 then prints a report to stderr, and SIGTERM lets the
+/// batch in flight finish before stopping.
+pub fn start() {
+    update(|status| status.started = Some(Instant::now()));
+    #[cfg(unix)]
+    {
+        use crate::log;
+        use crate::sys::on_signal;
+        use nix::sys::signal::Signal;
+        // Failing to install a handler only costs its feature, which isn't worth failing over
+        let _ = on_signal(Signal::SIGUSR1, || log::report(&report()));
+        let _ = on_signal(Signal::SIGTERM, || {
+            log::info("received SIGTERM, finishing the batch in flight");
+            DRAINING.store(true, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Passes SIGINT on to the process group of the command in flight, which with --setsid is out of
+/// reach of Ctrl-C itself. No further batches are started. Calling this again, as each batch
+/// replay does, has no further effect.
+#[cfg(unix)]
+pub fn forward_interrupts() {
+    use crate::log;
+    use crate::sys::{on_signal, signal_group};
+    use nix::sys::signal::Signal;
+    static FORWARDING: std::sync::Once = std::sync::Once::new();
+    FORWARDING.call_once(|| {
+        let _ = on_signal(Signal::SIGINT, || {
+            log::info("received SIGINT, interrupting the command in flight");
+            DRAINING.store(true, Ordering::Relaxed);
+            match GROUP.load(Ordering::Relaxed) {
+                0 => {}
+                group => signal_group(group, Signal::SIGINT),
+            }
+        });
+    });
+}
+
+/// Stops starting batches at `deadline`, as on SIGTERM, and with `kill` terminates the command
+/// in flight then too.
+pub fn set_deadline(deadline: SystemTime, kill: bool) {
+    std::thread::spawn(move || {
+        // Sleep in short steps so that the clock being changed is noticed
+        while let Ok(left) = deadline.duration_since(SystemTime::now()) {
+            std::thread::sleep(left.min(Duration::from_secs(1)));
+        }
+        crate::log::info("the deadline has passed, so no more batches will be started");
+        PAST_DEADLINE.store(true, Ordering::Relaxed);
+        DRAINING.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        if kill {
+            use crate::sys::{signal_group, signal_process};
+            use nix::sys::signal::Signal;
+            match GROUP.load(Ordering::Relaxed) {
+                0 => {
+                    let children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+                    children.iter().for_each(|&pid| signal_process(pid, Signal::SIGTERM));
+                }
+                group => signal_group(group, Signal::SIGTERM),
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = kill;
+    });
+}
+
+/// Whether the --deadline has passed.
+pub fn past_deadline() -> bool {
+    PAST_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Records a command in flight, for --deadline-kill.
+#[cfg(unix)]
+pub fn child_started(pid: u32) {
+    CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).push(pid);
+}
+
+#[cfg(unix)]
+pub fn child_finished(pid: u32) {
+    CHILDREN.lock().unwrap_or_else(|e| e.into_inner()).retain(|&child| child != pid);
+}
+
+/// Records the process group of a command started with --setsid.
+#[cfg(unix)]
+pub fn group_started(pid: u32) {
+    GROUP.store(pid, Ordering::Relaxed);
+}
+
+/// Sends SIGTERM to whatever is left of a command's process group once the command itself has
+/// exited, such as helpers it forked that would otherwise keep holding its tempfiles.
+#[cfg(unix)]
+pub fn group_finished(pid: u32) {
+    GROUP.store(0, Ordering::Relaxed);
+    crate::sys::signal_group(pid, nix::sys::signal::Signal::SIGTERM);
+}
+
+/// Whether xtemp has been asked to stop once the batch in flight is done.
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+pub fn batch_started(batch: usize) {
+    update(|status| status.batch = batch);
+}
+
+/// The number of the batch in flight or last run, or 0 before the first.
+pub fn current_batch() -> usize {
+    STATUS.lock().map_or(0, |status| status.batch)
+}
+
+pub fn command_started(program: &str, tempfiles: usize) {
+    update(|status| status.command = Some(format!("{} with {} tempfiles", program, tempfiles)));
+}
+
+pub fn batch_finished(lines: usize, code: i32) {
+    update(|status| {
+        status.lines += lines;
+        status.failures += (code != 0) as usize;
+        status.command = None;
+    });
+}
+
+fn report() -> String {
+    let Ok(status) = STATUS.lock() else {
+        return "status unavailable".into();
+    };
+    let elapsed = status.started.map(|t| t.elapsed()).unwrap_or_default();
+    let mut report = format!(
+        "batch {}, {} lines processed, {} failed batches, {} elapsed",
+        status.batch,
+        status.lines,
+        status.failures,
+        format_elapsed(elapsed)
+    );
+    if let Some(ref command) = status.command {
+        report.push_str("\nrunning ");
+        report.push_str(command);
+    }
+    report
+}
+
+/// Formats a duration like "1h02m03s".
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
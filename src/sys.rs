@@ -0,0 +1,540 @@
+//! Platform-specific helpers. Everything that depends on Unix APIs lives here, alongside a
+//! portable fallback.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+
+#[cfg(unix)]
+pub fn get_max_open_files() -> usize {
+    use nix::sys::resource::{getrlimit, Resource};
+    match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok((soft, _hard)) => soft as usize,
+        Err(_) => 1024, // fallback
+    }
+}
+
+/// There's no equivalent of RLIMIT_NOFILE on other platforms, so use a typical soft limit.
+#[cfg(not(unix))]
+pub fn get_max_open_files() -> usize {
+    1024
+}
+
+/// Returns the system limit on the combined size of the arguments and environment of a new
+/// process.
+#[cfg(unix)]
+pub fn get_arg_max() -> usize {
+    use nix::unistd::{sysconf, SysconfVar};
+    match sysconf(SysconfVar::ARG_MAX) {
+        Ok(Some(n)) if n > 0 => n as usize,
+        _ => 128 * 1024, // fallback
+    }
+}
+
+/// On Windows the limit applies to the command line alone, which is at most 32767 characters.
+#[cfg(not(unix))]
+pub fn get_arg_max() -> usize {
+    32767
+}
+
+/// Returns the limit on the size of any one argument or environment string of a new process,
+/// which on Linux is 32 pages.
+#[cfg(target_os = "linux")]
+pub fn get_max_arg_strlen() -> usize {
+    use nix::unistd::{sysconf, SysconfVar};
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(n)) if n > 0 => n as usize * 32,
+        _ => 128 * 1024, // fallback
+    }
+}
+
+/// Elsewhere only the combined size is limited.
+#[cfg(not(target_os = "linux"))]
+pub fn get_max_arg_strlen() -> usize {
+    get_arg_max()
+}
+
+/// Size of an argument as counted against ARG_MAX: its bytes, the nul terminator, and the pointer
+/// to it in argv.
+#[cfg(unix)]
+pub fn arg_cost(arg: &OsStr) -> usize {
+    arg.len() + 1 + std::mem::size_of::<*const u8>()
+}
+
+/// Size of an argument within a Windows command line: its characters plus a separating space and
+/// the quotes that may be added around it.
+#[cfg(not(unix))]
+pub fn arg_cost(arg: &OsStr) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+    arg.encode_wide().count() + 3
+}
+
+/// Returns the size of the environment as counted against ARG_MAX.
+#[cfg(unix)]
+pub fn get_env_size() -> usize {
+    std::env::vars_os()
+        .map(|(k, v)| k.len() + v.len() + 2 + std::mem::size_of::<*const u8>())
+        .sum()
+}
+
+/// The Windows environment block doesn't count against the command line limit.
+#[cfg(not(unix))]
+pub fn get_env_size() -> usize {
+    0
+}
+
+/// Converts bytes written out with `OsStr::as_encoded_bytes` back into an OsString. Elsewhere
+/// than on Unix, where any bytes go, they're taken as UTF-8.
+#[cfg(unix)]
+pub fn os_string(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes).to_owned()
+}
+
+#[cfg(not(unix))]
+pub fn os_string(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+/// Takes part of the bytes of an `OsStr::as_encoded_bytes` as an OsStr again. Elsewhere than on
+/// Unix the part must have been split off next to ASCII characters or whole strings.
+#[cfg(unix)]
+pub fn os_str(bytes: &[u8]) -> &OsStr {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn os_str(bytes: &[u8]) -> &OsStr {
+    unsafe { OsStr::from_encoded_bytes_unchecked(bytes) }
+}
+
+/// Returns the space available to us on the filesystem containing `dir`, in bytes.
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: statvfs succeeded, so it filled in the struct
+    let stat = unsafe { stat.assume_init() };
+    // The field types vary between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Fsyncs the directory containing `path` so that newly created entries in it are durable.
+#[cfg(unix)]
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => std::fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Directories can't be opened as files elsewhere; syncing the files themselves has to do.
+#[cfg(not(unix))]
+pub fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Resolves a group name or numeric ID to a GID.
+#[cfg(unix)]
+pub fn parse_group(s: &str) -> std::result::Result<u32, String> {
+    use nix::unistd::Group;
+    if let Ok(gid) = s.parse() {
+        return Ok(gid);
+    }
+    match Group::from_name(s) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        Ok(None) => Err(format!("no such group: {}", s)),
+        Err(e) => Err(format!("could not look up group {}: {}", s, e)),
+    }
+}
+
+/// Applies --file-mode and --file-group to a newly created temporary directory. The directory
+/// is made searchable by whoever the mode lets read the files.
+#[cfg(unix)]
+pub fn set_dir_owner_and_mode(dir: &Path, mode: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::{chown, PermissionsExt};
+    if let Some(mode) = mode {
+        set_permissions(dir, Permissions::from_mode(0o700 | mode | (mode & 0o444) >> 2))?;
+    }
+    if let Some(gid) = gid {
+        chown(dir, None, Some(gid))?;
+    }
+    Ok(())
+}
+
+/// Creates a file in memory for --memfd, returning it along with the path that it can be opened
+/// by. It's left open across exec, so that the same path works for child processes.
+#[cfg(target_os = "linux")]
+pub fn memfd() -> io::Result<(std::fs::File, std::path::PathBuf)> {
+    use std::os::unix::io::FromRawFd;
+    let fd = unsafe { libc::memfd_create(c"xtemp".as_ptr(), 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    Ok((file, format!("/proc/self/fd/{}", fd).into()))
+}
+
+/// Applies --file-mode and --file-group to a newly created tempfile.
+#[cfg(unix)]
+pub fn set_file_owner_and_mode(
+    file: &std::fs::File,
+    mode: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::{fchown, PermissionsExt};
+    // Set these explicitly rather than at creation time so that the umask doesn't apply
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    if let Some(gid) = gid {
+        fchown(file, None, Some(gid))?;
+    }
+    Ok(())
+}
+
+/// Returns a command that runs `script` with the system shell. Arguments added to the command
+/// become the script's positional parameters.
+#[cfg(unix)]
+pub fn shell_command(script: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(script).arg("xtemp");
+    cmd
+}
+
+/// Arguments added to the command are appended to the script.
+#[cfg(not(unix))]
+pub fn shell_command(script: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(script);
+    cmd
+}
+
+/// Returns the exit code of a finished process, following the shell convention of 128 plus the
+/// signal number for processes killed by a signal.
+#[cfg(unix)]
+pub fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .or_else(|| status.signal().map(|sig| 128 + sig))
+        .unwrap_or(-1)
+}
+
+#[cfg(not(unix))]
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(-1)
+}
+
+/// Resources used by a finished command
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    /// Peak resident set size, in KiB
+    pub max_rss: u64,
+    pub user: Duration,
+    pub system: Duration,
+}
+
+/// Waits for a child to exit, returning its resource usage along with its exit status.
+#[cfg(unix)]
+pub fn wait_with_usage(child: &mut Child) -> io::Result<(ExitStatus, Option<Usage>)> {
+    wait_for(child, 0).map(|exited| exited.expect("wait4 returned before the child exited"))
+}
+
+/// Like `wait_with_usage`, but returns None straight away if the child hasn't exited yet.
+#[cfg(unix)]
+pub fn try_wait_with_usage(child: &mut Child) -> io::Result<Option<(ExitStatus, Option<Usage>)>> {
+    wait_for(child, libc::WNOHANG)
+}
+
+#[cfg(unix)]
+fn wait_for(child: &mut Child, options: i32) -> io::Result<Option<(ExitStatus, Option<Usage>)>> {
+    use std::os::unix::process::ExitStatusExt;
+    let mut status = 0;
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    loop {
+        let pid = child.id() as libc::pid_t;
+        match unsafe { libc::wait4(pid, &mut status, options, usage.as_mut_ptr()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => return Ok(None),
+            _ => break,
+        }
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    // macOS reports the peak RSS in bytes rather than KiB
+    let max_rss = if cfg!(target_os = "macos") { usage.ru_maxrss / 1024 } else { usage.ru_maxrss };
+    Ok(Some((
+        ExitStatus::from_raw(status),
+        Some(Usage {
+            max_rss: max_rss as u64,
+            user: time(usage.ru_utime),
+            system: time(usage.ru_stime),
+        }),
+    )))
+}
+
+/// Resource usage isn't reported on this platform.
+#[cfg(not(unix))]
+pub fn wait_with_usage(child: &mut Child) -> io::Result<(ExitStatus, Option<Usage>)> {
+    child.wait().map(|status| (status, None))
+}
+
+/// Settings applied to a child process between fork and exec.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct ChildSetup {
+    /// Increment to the child's niceness
+    pub nice: Option<i32>,
+    /// I/O priority as encoded for ioprio_set(2)
+    #[cfg(target_os = "linux")]
+    pub ioprio: Option<i32>,
+    /// Limit on the child's address space, in bytes
+    pub limit_mem: Option<u64>,
+    /// Limit on the child's CPU time, in seconds
+    pub limit_cpu: Option<u64>,
+    /// Limit on the size of files the child may create, in bytes
+    pub limit_fsize: Option<u64>,
+    /// Whether the child starts a session, and so a process group, of its own
+    pub setsid: bool,
+}
+
+#[cfg(unix)]
+impl ChildSetup {
+    pub fn apply(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        let setup = self.clone();
+        // Safety: the closure only makes async-signal-safe system calls
+        unsafe {
+            cmd.pre_exec(move || setup.setup_child());
+        }
+    }
+
+    fn setup_child(&self) -> io::Result<()> {
+        if self.setsid && unsafe { libc::setsid() } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(n) = self.nice {
+            // nice(2) can legitimately return -1, so errno is the only way to detect failure
+            nix::errno::Errno::clear();
+            if unsafe { libc::nice(n) } == -1 && nix::errno::Errno::last_raw() != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        use nix::sys::resource::{setrlimit, Resource};
+        let limits = [
+            (Resource::RLIMIT_AS, self.limit_mem),
+            (Resource::RLIMIT_CPU, self.limit_cpu),
+            (Resource::RLIMIT_FSIZE, self.limit_fsize),
+        ];
+        for (resource, limit) in limits {
+            if let Some(limit) = limit {
+                setrlimit(resource, limit as _, limit as _)?;
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ioprio) = self.ioprio {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends `signal` to the process group led by `pid`, as started with ChildSetup::setsid. It's
+/// fine for the group to be gone already.
+#[cfg(unix)]
+pub fn signal_group(pid: u32, signal: nix::sys::signal::Signal) {
+    let _ = nix::sys::signal::killpg(nix::unistd::Pid::from_raw(pid as i32), signal);
+}
+
+/// Sends `signal` to a process, which may have exited already.
+#[cfg(unix)]
+pub fn signal_process(pid: u32, signal: nix::sys::signal::Signal) {
+    let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal);
+}
+
+/// Returns whether our stdout or stderr is a pipe that's been closed at the other end, as when
+/// xtemp's output is piped into head(1) and it's exited.
+#[cfg(unix)]
+pub fn output_closed() -> bool {
+    let mut fds = [1, 2].map(|fd| libc::pollfd { fd, events: 0, revents: 0 });
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, 0) } <= 0 {
+        return false;
+    }
+    fds.iter().any(|fd| fd.revents & (libc::POLLERR | libc::POLLHUP) != 0)
+}
+
+#[cfg(not(unix))]
+pub fn output_closed() -> bool {
+    false
+}
+
+/// Reads a single keypress from a terminal, without waiting for Enter. Returns None at the end of
+/// the terminal's input.
+#[cfg(unix)]
+pub fn read_key(tty: &mut std::fs::File) -> io::Result<Option<u8>> {
+    use io::Read;
+    use std::os::fd::AsRawFd;
+    let fd = tty.as_raw_fd();
+    let mut saved = std::mem::MaybeUninit::uninit();
+    if unsafe { libc::tcgetattr(fd, saved.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: tcgetattr succeeded, so it filled in the settings
+    let saved = unsafe { saved.assume_init() };
+    let mut single = saved;
+    single.c_lflag &= !libc::ICANON;
+    single.c_cc[libc::VMIN] = 1;
+    single.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &single) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut key = [0u8];
+    let read = loop {
+        match tty.read(&mut key) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            read => break read,
+        }
+    };
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    Ok((read? > 0).then_some(key[0]))
+}
+
+/// Parses an ionice(1)-style I/O scheduling class and optional level, such as "idle",
+/// "best-effort:7" or "2:0", into an ioprio_set(2) priority value.
+#[cfg(target_os = "linux")]
+pub fn parse_ionice(s: &str) -> std::result::Result<i32, String> {
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+    let (class, level) = match s.split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (s, None),
+    };
+    let class = match class {
+        "0" | "none" => 0,
+        "1" | "realtime" => 1,
+        "2" | "best-effort" => 2,
+        "3" | "idle" => 3,
+        _ => return Err(format!("invalid I/O scheduling class: {}", class)),
+    };
+    let level = match level {
+        Some(level) => match level.parse() {
+            Ok(level @ 0..=7) => level,
+            _ => return Err(format!("invalid I/O priority level: {}", level)),
+        },
+        // Same default as ionice(1)
+        None => 4,
+    };
+    Ok((class << IOPRIO_CLASS_SHIFT) | level)
+}
+
+/// Returns whether stdin is a regular file, as opposed to a pipe or terminal.
+#[cfg(unix)]
+pub fn stdin_is_file() -> bool {
+    use std::os::fd::AsFd;
+    io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .and_then(|fd| std::fs::File::from(fd).metadata())
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_file() -> bool {
+    false
+}
+
+/// Binds a Unix socket at `path`. A socket left behind by a server that's no longer running is
+/// replaced, but anything else already at `path` is an error.
+#[cfg(unix)]
+pub fn bind_socket(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    let stale = std::fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Opens a file descriptor inherited from our parent, such as the one given with --progress-fd.
+/// The descriptor is duplicated so that it stays open for anything else that shares it.
+#[cfg(unix)]
+pub fn open_fd(fd: i32) -> io::Result<std::fs::File> {
+    use std::os::fd::FromRawFd;
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+    if dup == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: the duplicate is a new descriptor that nothing else owns
+    Ok(unsafe { std::fs::File::from_raw_fd(dup) })
+}
+
+/// Write ends of the pipes that forward each signal to its handler thread
+#[cfg(unix)]
+static SIGNAL_PIPES: [std::sync::atomic::AtomicI32; 32] =
+    [const { std::sync::atomic::AtomicI32::new(-1) }; 32];
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    use std::sync::atomic::Ordering;
+    if let Some(fd) = SIGNAL_PIPES.get(signal as usize) {
+        let fd = fd.load(Ordering::Relaxed);
+        // Safety: write(2) is async-signal-safe. If the pipe is full, the handler thread already
+        // has a signal to handle
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Calls `f` on a thread of its own each time the process receives `signal`. Since the handler
+/// doesn't survive exec, commands still start with the default disposition.
+#[cfg(unix)]
+pub fn on_signal(
+    signal: nix::sys::signal::Signal,
+    f: impl Fn() + Send + 'static,
+) -> io::Result<()> {
+    use io::Read;
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+    use std::os::fd::IntoRawFd;
+    let (mut reader, writer) = io::pipe()?;
+    let fd = writer.into_raw_fd();
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    SIGNAL_PIPES[signal as usize].store(fd, std::sync::atomic::Ordering::Relaxed);
+    let action =
+        SigAction::new(SigHandler::Handler(forward_signal), SaFlags::SA_RESTART, SigSet::empty());
+    // Safety: the handler only makes async-signal-safe calls
+    unsafe { sigaction(signal, &action) }?;
+    std::thread::spawn(move || {
+        let mut buf = [0u8];
+        while reader.read(&mut buf).is_ok_and(|n| n > 0) {
+            f();
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Templates for the contents of tempfiles, for --template and --file-format. Placeholders in
+//! braces are replaced for each record; anything else, including braces that don't form a
+//! placeholder, is copied as is, so templates for formats like JSON don't need escaping.
+
+use std::fmt::Write;
+
+/// A part of a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// {} or {line}: the whole record
+    Record,
+    /// {N}: the Nth whitespace-separated field of the record, counting from 1
+    Field(usize),
+    /// {lineno}: the record's line number in the input, counting from 1
+    LineNumber,
+    /// {batch}: the number of the batch the record is in
+    Batch,
+}
+
+/// What the placeholders are filled in from
+pub struct Context<'a> {
+    pub record: &'a str,
+    pub lineno: usize,
+    pub batch: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let placeholder = after.find('}').and_then(|end| {
+                let segment = match &after[..end] {
+                    "" | "line" => Segment::Record,
+                    "lineno" => Segment::LineNumber,
+                    "batch" => Segment::Batch,
+                    name => match name.parse() {
+                        Ok(n) if n > 0 => Segment::Field(n),
+                        _ => return None,
+                    },
+                };
+                Some((segment, end))
+            });
+            match placeholder {
+                Some((segment, end)) => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(segment);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    literal.push('{');
+                    rest = after;
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Template { segments }
+    }
+
+    /// Returns the size of the template's literal text, which every rendering includes.
+    pub fn literal_len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => s.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Appends the template with the placeholders filled in from `context` to `out`.
+    pub fn render(&self, context: &Context, out: &mut String) {
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Record => out.push_str(context.record),
+                Segment::Field(n) => {
+                    out.push_str(context.record.split_whitespace().nth(n - 1).unwrap_or(""))
+                }
+                Segment::LineNumber => {
+                    let _ = write!(out, "{}", context.lineno);
+                }
+                Segment::Batch => {
+                    let _ = write!(out, "{}", context.batch);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Transcoding input to UTF-8 for --from-encoding.
+
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read};
+
+/// Size of the chunks read from the underlying reader
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Parses an encoding label such as "latin1" or "utf-16le".
+pub fn parse_encoding(s: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(s.as_bytes()).ok_or_else(|| format!("unknown encoding: {}", s))
+}
+
+/// Converts UTF-8 back to `encoding`, as for records from a Transcoder that are written out
+/// again. Characters it can't represent become HTML character references.
+pub fn encode<'a>(encoding: &'static Encoding, s: &'a str) -> Cow<'a, [u8]> {
+    // encoding_rs only decodes UTF-16
+    if encoding == UTF_16LE {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<u8>>().into()
+    } else if encoding == UTF_16BE {
+        s.encode_utf16().flat_map(u16::to_be_bytes).collect::<Vec<u8>>().into()
+    } else {
+        encoding.encode(s).0
+    }
+}
+
+/// Reads UTF-8 decoded from another encoding. Malformed input is replaced with U+FFFD.
+pub struct Transcoder<R> {
+    inner: R,
+    decoder: Decoder,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// How much of `output` has been consumed
+    pos: usize,
+}
+
+impl<R: Read> Transcoder<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> Self {
+        Transcoder {
+            inner,
+            decoder: encoding.new_decoder(),
+            input: vec![0; CHUNK_SIZE],
+            output: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Transcoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.output.len() {
+            let n = match self.inner.read(&mut self.input) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            // The end of input isn't passed on to the decoder, since with --follow there may be
+            // more to come. All it could be holding onto is a truncated character
+            if n == 0 {
+                break;
+            }
+            let capacity = self.decoder.max_utf8_buffer_length(n).unwrap_or(n * 3 + 16);
+            self.output.resize(capacity, 0);
+            let (_, _, written, _) =
+                self.decoder.decode_to_utf8(&self.input[..n], &mut self.output, false);
+            self.output.truncate(written);
+            self.pos = 0;
+        }
+        Ok(&self.output[self.pos..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos = (self.pos + amount).min(self.output.len());
+    }
+}
@@ -0,0 +1,99 @@
+//! The --persistent protocol. The worker is spawned once and receives each batch on its stdin as
+//! the tempfile paths, one per line, followed by an empty line. Once it's done with the batch it
+//! writes a status line to its stdout: "done" on success, or "done N" with an exit code. Anything
+//! else it writes to stdout is copied to xtemp's stdout.
+
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use crate::{sys, Result, XtempError};
+
+pub struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    pub fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| XtempError::SubprocessFailed(e.to_string()))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(Worker { child, stdin, stdout })
+    }
+
+    /// Sends a batch to the worker and waits for its status line, returning the exit code it
+    /// reported.
+    pub fn run_batch<'a>(&mut self, paths: impl IntoIterator<Item = &'a OsStr>) -> Result<i32> {
+        let mut request = Vec::new();
+        for path in paths {
+            request.extend_from_slice(path.as_encoded_bytes());
+            request.push(b'\n');
+        }
+        request.push(b'\n');
+        if self.stdin.write_all(&request).and_then(|_| self.stdin.flush()).is_err() {
+            return Err(self.exited_early());
+        }
+
+        let mut stdout = io::stdout();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.stdout.read_line(&mut line) {
+                Ok(0) => return Err(self.exited_early()),
+                Ok(_) => {}
+                Err(e) => return Err(XtempError::SubprocessFailed(e.to_string())),
+            }
+            if let Some(status) = parse_status(line.trim_end_matches(['\n', '\r'])) {
+                return status;
+            }
+            stdout.write_all(line.as_bytes()).map_err(XtempError::FailedToWrite)?;
+        }
+    }
+
+    /// Closes the worker's stdin, which tells it there are no more batches, and waits for it to
+    /// exit.
+    pub fn finish(self) -> Result<()> {
+        let Worker { mut child, stdin, mut stdout } = self;
+        drop(stdin);
+        // Pass along anything it writes on its way out
+        io::copy(&mut stdout, &mut io::stdout()).map_err(XtempError::FailedToWrite)?;
+        let status = child.wait().map_err(|_| {
+            XtempError::SubprocessFailed("failed to wait for persistent command".into())
+        })?;
+        if !status.success() {
+            return Err(XtempError::SubprocessFailed(format!(
+                "persistent command exited with code {}",
+                sys::exit_code(status)
+            )));
+        }
+        Ok(())
+    }
+
+    fn exited_early(&mut self) -> XtempError {
+        let code = self.child.wait().map(sys::exit_code).unwrap_or(-1);
+        XtempError::SubprocessFailed(format!(
+            "persistent command exited with code {} before finishing its batch",
+            code
+        ))
+    }
+}
+
+/// Parses a status line, returning None if the line isn't one.
+fn parse_status(line: &str) -> Option<Result<i32>> {
+    match line.strip_prefix("done")? {
+        "" => Some(Ok(0)),
+        code => match code.strip_prefix(' ')?.trim().parse() {
+            Ok(code) => Some(Ok(code)),
+            Err(_) => Some(Err(XtempError::SubprocessFailed(format!(
+                "invalid status line from persistent command: {}",
+                line
+            )))),
+        },
+    }
+}